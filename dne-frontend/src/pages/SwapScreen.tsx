@@ -23,6 +23,11 @@ import {notifyWithLink, ToastNotificaiton} from "../components/toastNotification
 import {toast} from "react-toastify";
 
 const fee = 0.003;
+// How far the pool can move against a trade before it fails, in basis points
+const slippageBps = 50;
+
+// The least a quoted amount may come out at, given the slippage tolerance
+const withSlippage = (amount: anchor.BN) => amount.muln(10_000 - slippageBps).divn(10_000);
 
 interface AssociatedAddresses {
     userTokenAccountA: PublicKey;
@@ -64,14 +69,14 @@ const getAssociatedAddresses = async (
         ASSOCIATED_PROGRAM_ID
     );
 
-    const [lpTokenAPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("pool_token_a"), tokenA.toBuffer()],
-        program.programId
-    );
-    const [lpTokenBPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("pool_token_b"), tokenB.toBuffer()],
+    // Read the vaults from the pool, since pools that have not migrated their vaults yet use the old addresses
+    const [liquidityPoolPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("liquidity_pool"), tokenA.toBuffer(), tokenB.toBuffer()],
         program.programId
     );
+    const liquidityPool = await program.account.liquidityPool.fetch(liquidityPoolPda);
+    const lpTokenAPda: PublicKey = liquidityPool.lpTokenA;
+    const lpTokenBPda: PublicKey = liquidityPool.lpTokenB;
 
     return {
         userTokenAccountA,
@@ -392,6 +397,12 @@ const Swap = () => {
         }
     }
 
+    // Every trade records a price observation in the pool's oracle account
+    const observationsPda = (pool: PublicKey) => PublicKey.findProgramAddressSync(
+        [Buffer.from("observations"), pool.toBuffer()],
+        program.programId
+    )[0];
+
     const handleSwap = async (props:poolActionProps) => {
 
         try {
@@ -401,11 +412,21 @@ const Swap = () => {
             transaction.recentBlockhash = blockhash;
             transaction.feePayer = props.walletPublicKey;
 
+            // Ask the pool what the swap pays out now, and accept up to the slippage tolerance less
+            const amountIn = new anchor.BN(tokenAAmount * 10 ** 9);
+            const quote = await program.methods
+                .quoteSwap(amountIn, reverseSwap)
+                .accountsStrict({ liquidityPool: props.poolPublicKey, lpToken: props.lpTokenMint })
+                .view();
+
             transaction.add(
                 await program.methods
                     .swapTokens(
-                        new anchor.BN(tokenAAmount * 10 ** 9),
+                        amountIn,
                         reverseSwap,
+                        withSlippage(quote.amountOut),
+                        0,
+                        new anchor.BN(Math.floor(Date.now() / 1000) + 60),
                     )
                     .accountsStrict({
                         liquidityPool: props.poolPublicKey,
@@ -416,6 +437,8 @@ const Swap = () => {
                         lpTokenA: props.lpTokenAPda,
                         lpTokenB: props.lpTokenBPda,
                         lpToken: props.lpTokenMint,
+                        referrerTokenAccount: null,
+                        observations: observationsPda(props.poolPublicKey),
                         user: props.walletPublicKey,
                         tokenProgram: TOKEN_2022_PROGRAM_ID,
                         systemProgram: anchor.web3.SystemProgram.programId,
@@ -457,12 +480,30 @@ const Swap = () => {
                 );
             }
 
+            // The pool keeps the LP tokens locked on its first deposit in this account
+            const [lockedLpTokenAccount] = PublicKey.findProgramAddressSync(
+                [Buffer.from("locked_liquidity"), props.poolPublicKey.toBuffer()],
+                program.programId
+            );
+
+            // Ask the pool what the deposit takes and mints now, and accept up to the slippage tolerance less
+            const amountA = new anchor.BN(tokenAAmount * 10 ** 9);
+            const amountB = new anchor.BN(tokenBAmount * 10 ** 9);
+            const quote = await program.methods
+                .quoteAddLiquidity(amountA, amountB)
+                .accountsStrict({ liquidityPool: props.poolPublicKey, lpToken: props.lpTokenMint })
+                .view();
+
             // Add liquidity to the pool
             transaction.add(
                 await program.methods
                     .addLiquidity(
-                        new anchor.BN(tokenAAmount * 10 ** 9),
-                        new anchor.BN(tokenBAmount * 10 ** 9),
+                        amountA,
+                        amountB,
+                        withSlippage(quote.amountA),
+                        withSlippage(quote.amountB),
+                        withSlippage(quote.lpOut),
+                        new anchor.BN(Math.floor(Date.now() / 1000) + 60),
                     )
                     .accountsStrict({
                         liquidityPool: props.poolPublicKey,
@@ -474,6 +515,8 @@ const Swap = () => {
                         lpTokenB: props.lpTokenBPda,
                         lpToken: props.lpTokenMint,
                         userLpTokenAccount: props.userTokenAccountLP,
+                        lockedLpTokenAccount: lockedLpTokenAccount,
+                        observations: observationsPda(props.poolPublicKey),
                         user: props.walletPublicKey,
                         tokenProgram: TOKEN_2022_PROGRAM_ID,
                         systemProgram: anchor.web3.SystemProgram.programId,
@@ -528,10 +571,20 @@ const Swap = () => {
                 );
             }
 console.log(lpTokenAmount)
+            // Ask the pool what the LP tokens are worth now, and accept up to the slippage tolerance less
+            const lpAmount = new anchor.BN(lpTokenAmount * 10 ** 9);
+            const quote = await program.methods
+                .quoteRemoveLiquidity(lpAmount)
+                .accountsStrict({ liquidityPool: props.poolPublicKey, lpToken: props.lpTokenMint })
+                .view();
+
             transaction.add(
                 await program.methods
                     .removeLiquidity(
-                        new anchor.BN(lpTokenAmount * 10 ** 9),
+                        lpAmount,
+                        withSlippage(quote.amountA),
+                        withSlippage(quote.amountB),
+                        new anchor.BN(Math.floor(Date.now() / 1000) + 60),
                     )
                     .accountsStrict({
                         liquidityPool: props.poolPublicKey,
@@ -543,6 +596,7 @@ console.log(lpTokenAmount)
                         lpTokenB: props.lpTokenBPda,
                         lpToken: props.lpTokenMint,
                         userLpTokenAccount: props.userTokenAccountLP,
+                        observations: observationsPda(props.poolPublicKey),
                         user: props.walletPublicKey,
                         tokenProgram: TOKEN_2022_PROGRAM_ID,
                         systemProgram: anchor.web3.SystemProgram.programId,
@@ -1,23 +1,25 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::spl_token::error::TokenError::InvalidMint;
+use anchor_lang::{system_program, Discriminator};
 use anchor_spl::token_interface::{
     spl_token_2022,
-    Mint, MintTo, Burn, TokenAccount, TokenInterface, Transfer,
-    mint_to, transfer, burn
+    Mint, MintTo, Burn, CloseAccount, TokenAccount, TokenInterface, TransferChecked,
+    mint_to, transfer_checked, burn, close_account
 };
 
-use fixed::types::I64F64;
-
 declare_id!("HFMM2nW3ARsBFEKsQnx5mxrTThMKiMGkyETeJ5i2zgNx");
 
+pub mod quote;
+
 #[program]
 pub mod digital_nomad_exchange {
     use super::*;
 
-    // First we initialize the program with the program context.
-    // This is the entry point for the program.
-    // It will create a new Liquidity Pool account and mint LP tokens to the user.
-    pub fn initialize(ctx: Context<CreateLiquidityPool>) -> Result<()> {
+    // The create_pool function is the entry point for a new pair.
+    // In one transaction it creates the Liquidity Pool account, both vaults and a fresh LP token mint owned by the pool.
+    // fee_bps is the swap fee in basis points and must be one of the tiers allowed by the global config.
+    // Without a fee the pool starts with the config's default fee.
+    pub fn create_pool(ctx: Context<CreateLiquidityPool>, fee_bps: Option<u16>) -> Result<()> {
+        let fee_bps = ctx.accounts.global_config.pool_fee_bps(fee_bps)?;
         let liquidity_pool = &mut ctx.accounts.liquidity_pool;
 
         let token_a = ctx.accounts.token_a_mint.key();
@@ -29,137 +31,823 @@ pub mod digital_nomad_exchange {
         liquidity_pool.lp_token_b = ctx.accounts.lp_token_b.key();
         liquidity_pool.lp_token = ctx.accounts.lp_token.key();
         liquidity_pool.owner = ctx.accounts.user.key();
+        liquidity_pool.fee_bps = fee_bps;
+        liquidity_pool.version = LiquidityPool::VERSION;
+        liquidity_pool.bump = ctx.bumps.liquidity_pool;
+        liquidity_pool.vault_a_bump = ctx.bumps.lp_token_a;
+        liquidity_pool.vault_b_bump = ctx.bumps.lp_token_b;
+        liquidity_pool.locked_bump = ctx.bumps.locked_lp_token_account;
 
         msg!("Token A (MINT): {}", liquidity_pool.token_a);
         msg!("Token B (MINT): {}", liquidity_pool.token_b);
 
+        // Record the pool so clients can list every pool from the registry
+        let pool = liquidity_pool.key();
+        PoolRegistry::push_in(&ctx.accounts.pool_registry, PoolEntry {
+            pool,
+            mint_a: token_a,
+            mint_b: token_b,
+        })?;
+        ctx.accounts.observations.load_init()?.initialize(pool, ctx.bumps.observations);
+
+        emit!(PoolCreated {
+            pool,
+            mint_a: token_a,
+            mint_b: token_b,
+            lp_mint: ctx.accounts.lp_token.key(),
+            owner: ctx.accounts.user.key(),
+            fee_bps,
+            slot: Clock::get()?.slot,
+        });
+
         Ok(())
     }
 
-    pub fn initialize_pda(ctx: Context<CreateLiquidityPoolPDA>) -> Result<()> {
+    // The initialize_config function creates the global config and the empty pool registry.
+    // It can only run once, signed by the program's upgrade authority, which becomes the program admin.
+    pub fn initialize_config(ctx: Context<InitializeConfig>, default_fee_bps: u16, fee_tiers: Vec<u16>) -> Result<()> {
+        let global_config = &mut ctx.accounts.global_config;
+        global_config.admin = ctx.accounts.admin.key();
+        global_config.treasury = ctx.accounts.admin.key();
+        global_config.set_fee_tiers(default_fee_bps, fee_tiers)?;
+        msg!("Global config initialized, admin: {}", global_config.admin);
         Ok(())
     }
 
-    // The add_liquidity function will add liquidity to the pool.
-    // It will transfer the token A and B from the user to the pool.
-    // It will mint LP tokens to the user.
-    pub fn add_liquidity(ctx: Context<AddLiquidity>, amount_a: u64, amount_b: u64) -> Result<()> {
-        let bump = ctx.bumps.liquidity_pool;
+    // The update_config function lets the program admin change the default fee and the allowed fee tiers.
+    // Existing pools keep their fee, the tiers apply to new pools and to later fee changes.
+    pub fn update_config(ctx: Context<ConfigAdmin>, default_fee_bps: u16, fee_tiers: Vec<u16>) -> Result<()> {
+        ctx.accounts.global_config.set_fee_tiers(default_fee_bps, fee_tiers)
+    }
 
-        // Transfer tokens from user to pool
-        ctx.accounts.transfer_to_pool_a(amount_a)?;
-        ctx.accounts.transfer_to_pool_b(amount_b)?;
+    // The set_treasury function lets the program admin change the wallet that receives the protocol fees.
+    pub fn set_treasury(ctx: Context<ConfigAdmin>, treasury: Pubkey) -> Result<()> {
+        ctx.accounts.global_config.treasury = treasury;
+        msg!("Treasury set to {}", treasury);
+        Ok(())
+    }
 
-        // Create Mint LP transaction with the liquidity pool PDA as the authority.
-        let cpi_accounts = MintTo {
-            mint: ctx.accounts.lp_token.to_account_info(),
-            to: ctx.accounts.user_lp_token_account.to_account_info(),
-            authority: ctx.accounts.liquidity_pool.to_account_info(), // LP PDA
-        };
+    // The propose_admin function is the first step of handing the program admin role over.
+    // The current admin stays in control until new_admin calls accept_admin.
+    // Proposing the default pubkey cancels a pending transfer.
+    pub fn propose_admin(ctx: Context<ConfigAdmin>, new_admin: Pubkey) -> Result<()> {
+        ctx.accounts.global_config.pending_admin = new_admin;
+        msg!("Proposed {} as the new admin", new_admin);
+        Ok(())
+    }
 
-        let mint_a = ctx.accounts.mint_a.key();
-        let mint_b = ctx.accounts.mint_b.key();
-        let seeds = &[
-            b"liquidity_pool",
-            mint_a.as_ref(),
-            mint_b.as_ref(),
-            &[bump],
-        ];
-        let signer_seeds = &[&seeds[..]];
+    // The accept_admin function completes an admin transfer, signed by the proposed admin.
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let global_config = &mut ctx.accounts.global_config;
+        global_config.admin = global_config.pending_admin;
+        global_config.pending_admin = Pubkey::default();
+        msg!("Admin role transferred to {}", global_config.admin);
+        Ok(())
+    }
+
+    // The set_fee function lets the pool owner change the swap fee, in basis points.
+    // The new fee has to be one of the fee tiers in the global config.
+    pub fn set_fee(ctx: Context<PoolAdmin>, fee_bps: u16) -> Result<()> {
+        ctx.accounts.global_config.check_fee_tier(fee_bps)?;
+        ctx.accounts.liquidity_pool.set_fee_bps(fee_bps)
+    }
+
+    // The set_protocol_fee function lets the program admin set the share of the swap fee that goes to the protocol, in basis points.
+    pub fn set_protocol_fee(ctx: Context<ProtocolFeeAdmin>, protocol_fee_bps: u16) -> Result<()> {
+        ctx.accounts.liquidity_pool.set_protocol_fee_bps(protocol_fee_bps)
+    }
+
+    // The set_dynamic_fee function lets the pool owner switch the pool between a flat fee and a dynamic fee.
+    // In dynamic fee mode the fee rises above fee_bps with recent price volatility and decays back to it.
+    pub fn set_dynamic_fee(ctx: Context<PoolAdmin>, dynamic_fee: bool) -> Result<()> {
+        ctx.accounts.liquidity_pool.set_dynamic_fee(dynamic_fee, Clock::get()?.slot);
+        Ok(())
+    }
+
+    // The update_pool_params function lets the pool owner change several parameters at once.
+    // Parameters left as None keep their current value.
+    pub fn update_pool_params(ctx: Context<PoolAdmin>, params: PoolParams) -> Result<()> {
+        let liquidity_pool = &mut ctx.accounts.liquidity_pool;
+        if let Some(fee_bps) = params.fee_bps {
+            ctx.accounts.global_config.check_fee_tier(fee_bps)?;
+            liquidity_pool.set_fee_bps(fee_bps)?;
+        }
+        if let Some(dynamic_fee) = params.dynamic_fee {
+            liquidity_pool.set_dynamic_fee(dynamic_fee, Clock::get()?.slot);
+        }
+        Ok(())
+    }
 
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
+    // The pause_pool function lets the pool owner stop swaps and deposits.
+    // Withdrawals stay open so LPs can always leave a paused pool.
+    pub fn pause_pool(ctx: Context<PoolAdmin>) -> Result<()> {
+        ctx.accounts.liquidity_pool.paused = true;
+        msg!("Pool paused");
+        Ok(())
+    }
+
+    // The unpause_pool function lets the pool owner open swaps and deposits again.
+    pub fn unpause_pool(ctx: Context<PoolAdmin>) -> Result<()> {
+        ctx.accounts.liquidity_pool.paused = false;
+        msg!("Pool unpaused");
+        Ok(())
+    }
+
+    // The propose_owner function is the first step of an ownership transfer.
+    // The current owner stays in control until new_owner calls accept_owner.
+    // Proposing the default pubkey cancels a pending transfer.
+    pub fn propose_owner(ctx: Context<PoolAdmin>, new_owner: Pubkey) -> Result<()> {
+        ctx.accounts.liquidity_pool.pending_owner = new_owner;
+        msg!("Proposed {} as the new owner", new_owner);
+        Ok(())
+    }
+
+    // The accept_owner function completes an ownership transfer, signed by the proposed owner.
+    pub fn accept_owner(ctx: Context<AcceptOwner>) -> Result<()> {
+        let liquidity_pool = &mut ctx.accounts.liquidity_pool;
+        liquidity_pool.owner = liquidity_pool.pending_owner;
+        liquidity_pool.pending_owner = Pubkey::default();
+        msg!("Ownership transferred to {}", liquidity_pool.owner);
+        Ok(())
+    }
+
+    // The migrate_pool function grows a pool account created with an older layout to the current size
+    // and upgrades it to the current version. Anyone can call it, the payer covers the extra rent.
+    // Pools created before deposits locked minimum liquidity also get their locked LP token account,
+    // since add_liquidity needs it.
+    pub fn migrate_pool(ctx: Context<MigratePool>) -> Result<()> {
+        let pool_info = ctx.accounts.liquidity_pool.to_account_info();
+        {
+            let data = pool_info.try_borrow_data()?;
+            require!(
+                data.len() >= LiquidityPool::LEGACY_SPACE && data[..8] == LiquidityPool::DISCRIMINATOR,
+                DneError::InvalidPoolAccount
+            );
+        }
+
+        let old_len = pool_info.data_len();
+        if old_len < LiquidityPool::SPACE {
+            let rent = Rent::get()?.minimum_balance(LiquidityPool::SPACE);
+            let missing_rent = rent.saturating_sub(pool_info.lamports());
+            if missing_rent > 0 {
+                let cpi_accounts = system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: pool_info.clone(),
+                };
+                system_program::transfer(
+                    CpiContext::new(ctx.accounts.system_program.to_account_info(), cpi_accounts),
+                    missing_rent,
+                )?;
+            }
+            pool_info.realloc(LiquidityPool::SPACE, true)?;
+        }
+
+        let mut liquidity_pool = LiquidityPool::try_deserialize(&mut &pool_info.try_borrow_data()?[..])?;
+        require_keys_eq!(ctx.accounts.lp_token_a.key(), liquidity_pool.lp_token_a, DneError::InvalidPoolAccount);
+        require_keys_eq!(ctx.accounts.lp_token_b.key(), liquidity_pool.lp_token_b, DneError::InvalidPoolAccount);
+        require_keys_eq!(ctx.accounts.lp_token.key(), liquidity_pool.lp_token, DneError::InvalidPoolAccount);
+        if liquidity_pool.version < LiquidityPool::TRACKED_RESERVES_VERSION {
+            let pool = pool_info.key();
+            let seeds = [b"liquidity_pool".as_ref(), liquidity_pool.token_a.as_ref(), liquidity_pool.token_b.as_ref()];
+            liquidity_pool.bump = Pubkey::find_program_address(&seeds, &crate::ID).1;
+            liquidity_pool.vault_a_bump = LiquidityPool::find_vault_bump(
+                b"pool_token_a", &pool, &liquidity_pool.token_a, &liquidity_pool.lp_token_a,
+            )?;
+            liquidity_pool.vault_b_bump = LiquidityPool::find_vault_bump(
+                b"pool_token_b", &pool, &liquidity_pool.token_b, &liquidity_pool.lp_token_b,
+            )?;
+        }
+        liquidity_pool.locked_bump = ctx.bumps.locked_lp_token_account;
+        liquidity_pool.upgrade(old_len, ctx.accounts.lp_token_a.amount, ctx.accounts.lp_token_b.amount)?;
+        liquidity_pool.try_serialize(&mut &mut pool_info.try_borrow_mut_data()?[..])?;
+        msg!("Pool migrated to version {}", liquidity_pool.version);
+        Ok(())
+    }
+
+    // The migrate_vaults function moves a pool whose vaults were seeded by the token mints
+    // onto vaults seeded by the pool key, so its mints can be used in other pools too.
+    // The balances are moved over, the old vaults are closed and their rent goes to the owner.
+    pub fn migrate_vaults(ctx: Context<MigrateVaults>) -> Result<()> {
+        let bump = ctx.accounts.liquidity_pool.bump;
+        let accounts = &ctx.accounts;
+        accounts.move_vault(bump, &accounts.mint_a, &accounts.old_lp_token_a, &accounts.lp_token_a)?;
+        accounts.move_vault(bump, &accounts.mint_b, &accounts.old_lp_token_b, &accounts.lp_token_b)?;
+
+        let lp_token_a = ctx.accounts.lp_token_a.key();
+        let lp_token_b = ctx.accounts.lp_token_b.key();
+        let liquidity_pool = &mut ctx.accounts.liquidity_pool;
+        liquidity_pool.lp_token_a = lp_token_a;
+        liquidity_pool.lp_token_b = lp_token_b;
+        liquidity_pool.vault_a_bump = ctx.bumps.lp_token_a;
+        liquidity_pool.vault_b_bump = ctx.bumps.lp_token_b;
+        msg!("Vaults migrated to {} and {}", lp_token_a, lp_token_b);
+        Ok(())
+    }
+
+    // The collect_protocol_fees function lets the program admin move the accrued protocol fees
+    // from the pool vaults to token accounts owned by the treasury.
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let bump = ctx.accounts.liquidity_pool.bump;
+
+        let amount_a = ctx.accounts.liquidity_pool.protocol_fees_a;
+        let amount_b = ctx.accounts.liquidity_pool.protocol_fees_b;
+        ctx.accounts.liquidity_pool.protocol_fees_a = 0;
+        ctx.accounts.liquidity_pool.protocol_fees_b = 0;
+        msg!("Collecting {} token A and {} token B in protocol fees", amount_a, amount_b);
+
+        if amount_a > 0 {
+            ctx.accounts.transfer_to_treasury_a(bump, amount_a)?;
+        }
+        if amount_b > 0 {
+            ctx.accounts.transfer_to_treasury_b(bump, amount_b)?;
+        }
+
+        Ok(())
+    }
+
+    // The sync function sets the reserves to the vault balances, less the protocol fees.
+    // Tokens sent straight to the vaults then go to the LPs. Anyone can call it.
+    // A pool without LP tokens has no LPs to give them to, and reserves set before the first deposit
+    // would price it, so those pools are rejected. Donations to them can be skimmed instead.
+    pub fn sync(ctx: Context<SyncReserves>) -> Result<()> {
+        require!(ctx.accounts.lp_token.supply > 0, DneError::InsufficientLiquidity);
+        let liquidity_pool = &mut ctx.accounts.liquidity_pool;
+        liquidity_pool.update_price_cumulative(Clock::get()?.unix_timestamp);
+        Observations::record_in(&ctx.accounts.observations, liquidity_pool)?;
+        (liquidity_pool.reserve_a, liquidity_pool.reserve_b) = liquidity_pool.vault_reserves(
+            ctx.accounts.lp_token_a.amount,
+            ctx.accounts.lp_token_b.amount,
+        )?;
+        msg!("Reserves synced to {} token A and {} token B", liquidity_pool.reserve_a, liquidity_pool.reserve_b);
+        Ok(())
+    }
+
+    // The skim function sends the vault balances above the reserves and protocol fees to the caller's token accounts.
+    // Anyone can call it.
+    pub fn skim(ctx: Context<Skim>) -> Result<()> {
+        let (excess_a, excess_b) = ctx.accounts.liquidity_pool.excess(
+            ctx.accounts.lp_token_a.amount,
+            ctx.accounts.lp_token_b.amount,
+        )?;
+        msg!("Skimming {} token A and {} token B", excess_a, excess_b);
+
+        let accounts = &ctx.accounts;
+        if excess_a > 0 {
+            accounts.transfer_excess(&accounts.lp_token_a, &accounts.mint_a, &accounts.to_token_a, excess_a)?;
+        }
+        if excess_b > 0 {
+            accounts.transfer_excess(&accounts.lp_token_b, &accounts.mint_b, &accounts.to_token_b, excess_b)?;
+        }
+        Ok(())
+    }
+
+    // The create_observations function creates the observation buffer of a pool made before pools had one.
+    // Pools made with create_pool already have it. Anyone can call it.
+    pub fn create_observations(ctx: Context<CreateObservations>) -> Result<()> {
+        let pool = ctx.accounts.liquidity_pool.key();
+        ctx.accounts.observations.load_init()?.initialize(pool, ctx.bumps.observations);
+        Ok(())
+    }
+
+    // The increase_observation_cardinality function grows a pool's observation buffer to hold cardinality observations.
+    // The new slots are filled as the pool trades, so older prices become available over time.
+    // Anyone can call it, the payer covers the extra rent. A smaller cardinality than the current one does nothing.
+    // An account can only grow by 10 KiB per instruction, so large buffers take a few calls.
+    pub fn increase_observation_cardinality(ctx: Context<IncreaseObservationCardinality>, cardinality: u16) -> Result<()> {
+        require!(cardinality <= Observations::MAX_CARDINALITY, DneError::TooManyObservations);
+        let info = ctx.accounts.observations.to_account_info();
+        let capacity = Observations::capacity(Observations::split(&info.try_borrow_data()?)?.1);
+        msg!("Observation cardinality is now {}", capacity);
+        Ok(())
+    }
+
+    // The observe function returns the pool's accumulators as they were seconds_ago seconds before now, for each entry.
+    // They come back as Anchor return data, so other programs can read them after a CPI.
+    // A time-weighted price between two entries is the wrapping difference of their cumulative prices
+    // divided by the seconds between them, and the same goes for the liquidity.
+    pub fn observe(ctx: Context<Observe>, seconds_ago: Vec<u32>) -> Result<Vec<Observation>> {
+        require!(seconds_ago.len() <= Observations::MAX_OBSERVE, DneError::TooManyObservations);
+        let now = Clock::get()?.unix_timestamp;
+        let info = ctx.accounts.observations.to_account_info();
+        let data = info.try_borrow_data()?;
+        let (observations, slots) = Observations::split(&data)?;
+        seconds_ago
+            .iter()
+            .map(|&seconds_ago| observations.observe(slots, &ctx.accounts.liquidity_pool, now, seconds_ago))
+            .collect()
+    }
+
+    // The view functions below change nothing and return their result as Anchor return data,
+    // so clients can simulate them and other programs can read them after a CPI.
+    // They run the same math as the instructions they quote, against the same reserves and fee.
+
+    // The get_reserves function returns the reserves the pool prices against and the LP token supply.
+    pub fn get_reserves(ctx: Context<PoolView>) -> Result<PoolReserves> {
+        Ok(PoolReserves {
+            reserve_a: ctx.accounts.liquidity_pool.reserve_a,
+            reserve_b: ctx.accounts.liquidity_pool.reserve_b,
+            lp_supply: ctx.accounts.lp_token.supply,
+        })
+    }
+
+    // The get_spot_price function returns the marginal price of each token in the other, scaled by quote::PRICE_SCALE.
+    pub fn get_spot_price(ctx: Context<PoolView>) -> Result<SpotPrice> {
+        let liquidity_pool = &ctx.accounts.liquidity_pool;
+        Ok(SpotPrice {
+            price_a: quote::spot_price(liquidity_pool.reserve_a, liquidity_pool.reserve_b)?,
+            price_b: quote::spot_price(liquidity_pool.reserve_b, liquidity_pool.reserve_a)?,
+        })
+    }
+
+    // The quote_swap function returns what swap_tokens would pay out for amount of token A, or of token B when reverse is set.
+    // The quote is for a swap without a referrer, at the fee the pool would charge in this slot.
+    pub fn quote_swap(ctx: Context<PoolView>, amount: u64, reverse: Option<bool>) -> Result<SwapQuote> {
+        require!(amount > 0, DneError::ZeroAmount);
+        let liquidity_pool = &ctx.accounts.liquidity_pool;
+        let reverse = reverse.unwrap_or(false);
+        let fee_bps = liquidity_pool.current_fee_bps(Clock::get()?.slot);
+        let (reserve_in, reserve_out) = liquidity_pool.trade_reserves(reverse);
+        Ok(SwapQuote {
+            amount_out: LiquidityPool::calculate_swap(reserve_in, reserve_out, amount, fee_bps)?,
+            fee_bps,
+            fee: quote::swap_fee(amount, fee_bps)?,
+            price_impact_bps: quote::price_impact(reserve_in, reserve_out, amount, fee_bps)?,
+        })
+    }
+
+    // The quote_add_liquidity function returns the amounts add_liquidity would take for the desired amounts,
+    // and the LP tokens it would mint for them.
+    pub fn quote_add_liquidity(ctx: Context<PoolView>, amount_a_desired: u64, amount_b_desired: u64) -> Result<AddLiquidityQuote> {
+        require!(amount_a_desired > 0 && amount_b_desired > 0, DneError::ZeroAmount);
+        let (reserve_a, reserve_b) = ctx.accounts.liquidity_pool.deposit_reserves(ctx.accounts.lp_token.supply);
+        let (amount_a, amount_b) = LiquidityPool::calculate_optimal_deposit(
+            reserve_a,
+            reserve_b,
+            amount_a_desired,
+            amount_b_desired,
+            0,
+            0,
+        )?;
+        let lp_out = LiquidityPool::calculate_lp_amount_to_mint(
+            LPDepositRequest {
+                token_a_balance: reserve_a,
+                token_b_balance: reserve_b,
+                lp_token_balance: ctx.accounts.lp_token.supply,
+                token_a_amount: amount_a,
+                token_b_amount: amount_b,
+            }
+        )?;
+        Ok(AddLiquidityQuote { amount_a, amount_b, lp_out })
+    }
+
+    // The quote_remove_liquidity function returns the amounts remove_liquidity would pay out for burning lp_amount LP tokens.
+    pub fn quote_remove_liquidity(ctx: Context<PoolView>, lp_amount: u64) -> Result<RemoveLiquidityQuote> {
+        require!(lp_amount > 0, DneError::ZeroAmount);
+        let lp_supply = ctx.accounts.lp_token.supply;
+        require!(lp_amount <= lp_supply, DneError::InsufficientLiquidity);
+        let (amount_a, amount_b) = LiquidityPool::calculate_token_amount_to_remove(
+            lp_amount,
+            lp_supply,
+            ctx.accounts.liquidity_pool.reserve_a,
+            ctx.accounts.liquidity_pool.reserve_b,
+        ).ok_or(DneError::MathOverflow)?;
+        Ok(RemoveLiquidityQuote { amount_a, amount_b })
+    }
+
+    // The add_liquidity function will add liquidity to the pool.
+    // It will transfer at most the desired amounts of token A and B from the user to the pool,
+    // only taking what matches the current reserve ratio, and never less than the minimum amounts.
+    // It will mint LP tokens to the user, failing if fewer than min_lp_out would be minted.
+    pub fn add_liquidity(
+        ctx: Context<AddLiquidity>,
+        amount_a_desired: u64,
+        amount_b_desired: u64,
+        amount_a_min: u64,
+        amount_b_min: u64,
+        min_lp_out: u64,
+        expires_at: i64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        LiquidityPool::check_deadline(now, expires_at)?;
+        ctx.accounts.liquidity_pool.update_price_cumulative(now);
+        Observations::record_in(&ctx.accounts.observations, &ctx.accounts.liquidity_pool)?;
+        require!(amount_a_desired > 0 && amount_b_desired > 0, DneError::ZeroAmount);
+        let bump = ctx.accounts.liquidity_pool.bump;
+        let is_initial_deposit = ctx.accounts.lp_token.supply == 0;
+        let (reserve_a, reserve_b) = ctx.accounts.liquidity_pool.deposit_reserves(ctx.accounts.lp_token.supply);
+
+        // Work out how much of each token the deposit actually needs
+        let (amount_a, amount_b) = LiquidityPool::calculate_optimal_deposit(
+            reserve_a,
+            reserve_b,
+            amount_a_desired,
+            amount_b_desired,
+            amount_a_min,
+            amount_b_min,
+        )?;
+        require!(amount_a > 0 && amount_b > 0, DneError::ZeroAmount);
 
         // Calculate the LP tokens to mint.
         let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(
             LPDepositRequest {
-                token_a_balance: ctx.accounts.lp_token_a.amount,
-                token_a_decimals: ctx.accounts.mint_a.decimals,
-                token_b_balance: ctx.accounts.lp_token_b.amount,
-                token_b_decimals: ctx.accounts.mint_b.decimals,
+                token_a_balance: reserve_a,
+                token_b_balance: reserve_b,
                 lp_token_balance: ctx.accounts.lp_token.supply,
-                lp_token_decimals: ctx.accounts.lp_token.decimals,
                 token_a_amount: amount_a,
                 token_b_amount: amount_b,
             }
-        );
+        )?;
+        require!(amount_to_mint > 0, DneError::ZeroAmount);
+        require!(amount_to_mint >= min_lp_out, DneError::SlippageExceeded);
+
+        let liquidity_pool = &mut ctx.accounts.liquidity_pool;
+        liquidity_pool.reserve_a = reserve_a.checked_add(amount_a).ok_or(DneError::MathOverflow)?;
+        liquidity_pool.reserve_b = reserve_b.checked_add(amount_b).ok_or(DneError::MathOverflow)?;
+
+        // Transfer tokens from user to pool
+        ctx.accounts.transfer_to_pool_a(amount_a)?;
+        ctx.accounts.transfer_to_pool_b(amount_b)?;
+
+        // The first deposit permanently locks MINIMUM_LIQUIDITY LP tokens in the pool's locked account.
+        // This keeps the LP supply from ever returning to zero, which blocks share inflation attacks.
+        if is_initial_deposit {
+            let locked_account = ctx.accounts.locked_lp_token_account.to_account_info();
+            ctx.accounts.mint_lp_tokens(bump, locked_account, LiquidityPool::MINIMUM_LIQUIDITY)?;
+        }
 
         // Execute the mint instruction.
-        mint_to(cpi_ctx, amount_to_mint)?;
+        let user_lp_account = ctx.accounts.user_lp_token_account.to_account_info();
+        ctx.accounts.mint_lp_tokens(bump, user_lp_account, amount_to_mint)?;
+
+        emit!(LiquidityAdded {
+            pool: ctx.accounts.liquidity_pool.key(),
+            user: ctx.accounts.user.key(),
+            amount_a,
+            amount_b,
+            lp_minted: amount_to_mint,
+            fee_a: 0,
+            fee_b: 0,
+            reserve_a: ctx.accounts.liquidity_pool.reserve_a,
+            reserve_b: ctx.accounts.liquidity_pool.reserve_b,
+            slot: Clock::get()?.slot,
+        });
 
         Ok(())
     }
 
-    pub fn remove_liquidity(ctx: Context<RemoveLiquidity>, amount: u64) -> Result<()> {
+    // The add_liquidity_single_sided function will add liquidity to the pool using only token A, or token B when reverse is set.
+    // Part of the deposit is swapped through the pool curve so the rest can be deposited at the new reserve ratio.
+    // The swap never leaves the pool, so the user only transfers in the one token.
+    // It will mint LP tokens to the user, failing if fewer than min_lp_out would be minted.
+    pub fn add_liquidity_single_sided(ctx: Context<AddLiquidity>, amount_in: u64, reverse: Option<bool>, min_lp_out: u64, expires_at: i64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        LiquidityPool::check_deadline(now, expires_at)?;
+        ctx.accounts.liquidity_pool.update_price_cumulative(now);
+        Observations::record_in(&ctx.accounts.observations, &ctx.accounts.liquidity_pool)?;
+        require!(amount_in > 0, DneError::ZeroAmount);
+        let bump = ctx.accounts.liquidity_pool.bump;
+        let reverse = reverse.unwrap_or(false);
+        let slot = Clock::get()?.slot;
+        let fee_bps = ctx.accounts.liquidity_pool.current_fee_bps(slot);
+        let (token_balance_in, token_balance_out) = ctx.accounts.liquidity_pool.trade_reserves(reverse);
+
+        // Calculate how much of the deposit is used, and the LP tokens to mint for it.
+        let (amount_used, amount_to_mint, protocol_fee) = LiquidityPool::calculate_single_sided_deposit(
+            token_balance_in,
+            token_balance_out,
+            ctx.accounts.lp_token.supply,
+            amount_in,
+            fee_bps,
+            ctx.accounts.liquidity_pool.protocol_fee_bps,
+        )?;
+        require!(amount_to_mint > 0, DneError::ZeroAmount);
+        require!(amount_to_mint >= min_lp_out, DneError::SlippageExceeded);
+        ctx.accounts.liquidity_pool.accrue_protocol_fee(reverse, protocol_fee)?;
+        let fee = quote::swap_fee(quote::single_sided_swap_amount(token_balance_in, amount_in, fee_bps)?, fee_bps)?;
+
+        // The internal swap moves the price, the balanced deposit after it does not
+        let reserves_after = (
+            token_balance_in.checked_add(amount_used - protocol_fee).ok_or(DneError::MathOverflow)?,
+            token_balance_out,
+        );
+        ctx.accounts.liquidity_pool.record_trade(slot, reverse, (token_balance_in, token_balance_out), reserves_after)?;
+        ctx.accounts.liquidity_pool.set_trade_reserves(reverse, reserves_after);
 
-        let bump = ctx.bumps.liquidity_pool;
+        // Transfer tokens from user to pool
+        if reverse {
+            ctx.accounts.transfer_to_pool_b(amount_used)?;
+        } else {
+            ctx.accounts.transfer_to_pool_a(amount_used)?;
+        }
 
+        // Execute the mint instruction.
+        let user_lp_account = ctx.accounts.user_lp_token_account.to_account_info();
+        ctx.accounts.mint_lp_tokens(bump, user_lp_account, amount_to_mint)?;
+
+        let (amount_a, amount_b, fee_a, fee_b) = if reverse { (0, amount_used, 0, fee) } else { (amount_used, 0, fee, 0) };
+        emit!(LiquidityAdded {
+            pool: ctx.accounts.liquidity_pool.key(),
+            user: ctx.accounts.user.key(),
+            amount_a,
+            amount_b,
+            lp_minted: amount_to_mint,
+            fee_a,
+            fee_b,
+            reserve_a: ctx.accounts.liquidity_pool.reserve_a,
+            reserve_b: ctx.accounts.liquidity_pool.reserve_b,
+            slot,
+        });
+
+        Ok(())
+    }
+
+    // The remove_liquidity function will burn LP tokens and return both tokens to the user.
+    // It fails if either side pays out less than min_amount_a or min_amount_b.
+    pub fn remove_liquidity(ctx: Context<RemoveLiquidity>, amount: u64, min_amount_a: u64, min_amount_b: u64, expires_at: i64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        LiquidityPool::check_deadline(now, expires_at)?;
+        ctx.accounts.liquidity_pool.update_price_cumulative(now);
+        Observations::record_in(&ctx.accounts.observations, &ctx.accounts.liquidity_pool)?;
+        require!(amount > 0, DneError::ZeroAmount);
+        require!(amount <= ctx.accounts.lp_token.supply, DneError::InsufficientLiquidity);
+
+        let bump = ctx.accounts.liquidity_pool.bump;
 
         // Burn LP tokens from user
         ctx.accounts.burn(bump, amount)?;
 
         // Calculate amount to transfer for each token
+        let (reserve_a, reserve_b) = (ctx.accounts.liquidity_pool.reserve_a, ctx.accounts.liquidity_pool.reserve_b);
         let (amount_a, amount_b) = LiquidityPool::calculate_token_amount_to_remove(
             amount,
             ctx.accounts.lp_token.supply,
-            ctx.accounts.lp_token_a.amount,
-            ctx.accounts.lp_token_b.amount
-        );
+            reserve_a,
+            reserve_b
+        ).ok_or(DneError::MathOverflow)?;
+        require!(amount_a >= min_amount_a && amount_b >= min_amount_b, DneError::SlippageExceeded);
+        let liquidity_pool = &mut ctx.accounts.liquidity_pool;
+        liquidity_pool.reserve_a = reserve_a - amount_a;
+        liquidity_pool.reserve_b = reserve_b - amount_b;
 
         // Transfer tokens to user
         ctx.accounts.transfer_from_pool_a(bump, amount_a)?;
         ctx.accounts.transfer_from_pool_b(bump, amount_b)?;
 
+        emit!(LiquidityRemoved {
+            pool: ctx.accounts.liquidity_pool.key(),
+            user: ctx.accounts.user.key(),
+            amount_a,
+            amount_b,
+            lp_burned: amount,
+            fee_a: 0,
+            fee_b: 0,
+            reserve_a: ctx.accounts.liquidity_pool.reserve_a,
+            reserve_b: ctx.accounts.liquidity_pool.reserve_b,
+            slot: Clock::get()?.slot,
+        });
+
         Ok(())
     }
 
-    pub fn swap_tokens(ctx: Context<SwapTokens>, amount: u64, reverse: Option<bool>) -> Result<()> {
-        let bump = ctx.bumps.liquidity_pool;
-
-        // Depending on the token the user is swapping, we need to transfer the tokens from the user to the pool
-        // Would like to refactor this in the future to use a struct to be more compact
-        let (token_in, token_mint_in, token_mint_in_decimals,
-            token_out, token_mint_out,token_mint_out_decimals,
-        ) = if reverse.unwrap_or(false) {
-            (
-                ctx.accounts.lp_token_b.clone(),
-                ctx.accounts.mint_b.key(),
-                ctx.accounts.mint_b.decimals,
-                ctx.accounts.lp_token_a.clone(),
-                ctx.accounts.mint_a.key(),
-                ctx.accounts.mint_a.decimals,
-            )
+    // The remove_liquidity_one_token function will burn LP tokens and return only the token with mint out_mint.
+    // The other side of the withdrawal is swapped back through the pool curve in the same instruction.
+    // It will fail if less than min_amount_out would be paid out.
+    // Like remove_liquidity, it stays open while the pool is paused.
+    pub fn remove_liquidity_one_token(ctx: Context<RemoveLiquidity>, amount: u64, out_mint: Pubkey, min_amount_out: u64, expires_at: i64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        LiquidityPool::check_deadline(now, expires_at)?;
+        ctx.accounts.liquidity_pool.update_price_cumulative(now);
+        Observations::record_in(&ctx.accounts.observations, &ctx.accounts.liquidity_pool)?;
+        require!(amount > 0, DneError::ZeroAmount);
+        require!(amount <= ctx.accounts.lp_token.supply, DneError::InsufficientLiquidity);
+        let out_is_a = if out_mint == ctx.accounts.mint_a.key() {
+            true
+        } else if out_mint == ctx.accounts.mint_b.key() {
+            false
         } else {
-            (
-                ctx.accounts.lp_token_a.clone(),
-                ctx.accounts.mint_a.key(),
-                ctx.accounts.mint_a.decimals,
-                ctx.accounts.lp_token_b.clone(),
-                ctx.accounts.mint_b.key(),
-                ctx.accounts.mint_b.decimals,
-            )
+            return err!(DneError::UnknownMint);
         };
 
+        let bump = ctx.accounts.liquidity_pool.bump;
+        let slot = Clock::get()?.slot;
+        let fee_bps = ctx.accounts.liquidity_pool.current_fee_bps(slot);
 
+        // Burn LP tokens from user
+        ctx.accounts.burn(bump, amount)?;
+
+        // Calculate amount to transfer of the requested token
+        let (token_balance_other, token_balance_out) = ctx.accounts.liquidity_pool.trade_reserves(out_is_a);
+        let amount_out = LiquidityPool::calculate_single_token_removal(
+            amount,
+            ctx.accounts.lp_token.supply,
+            token_balance_out,
+            token_balance_other,
+            fee_bps,
+        )?;
+        require!(amount_out > 0, DneError::ZeroAmount);
+        require!(amount_out >= min_amount_out, DneError::SlippageExceeded);
+
+        // The share of the other token is swapped back into the pool, so it pays the protocol fee
+        let (_, amount_swapped) = LiquidityPool::calculate_token_amount_to_remove(
+            amount,
+            ctx.accounts.lp_token.supply,
+            token_balance_out,
+            token_balance_other
+        ).ok_or(DneError::MathOverflow)?;
+        let protocol_fee = quote::protocol_fee(
+            amount_swapped,
+            fee_bps,
+            ctx.accounts.liquidity_pool.protocol_fee_bps,
+        )?;
+        ctx.accounts.liquidity_pool.accrue_protocol_fee(out_is_a, protocol_fee)?;
+        let fee = quote::swap_fee(amount_swapped, fee_bps)?;
+
+        // The other token goes in and the requested token comes out
+        let reserves_after = (token_balance_other - protocol_fee, token_balance_out - amount_out);
+        ctx.accounts.liquidity_pool.record_trade(slot, out_is_a, (token_balance_other, token_balance_out), reserves_after)?;
+        ctx.accounts.liquidity_pool.set_trade_reserves(out_is_a, reserves_after);
+
+        // Transfer tokens to user
+        if out_is_a {
+            ctx.accounts.transfer_from_pool_a(bump, amount_out)?;
+        } else {
+            ctx.accounts.transfer_from_pool_b(bump, amount_out)?;
+        }
+
+        // The fee is paid in the token swapped back into the pool
+        let (amount_a, amount_b, fee_a, fee_b) = if out_is_a { (amount_out, 0, 0, fee) } else { (0, amount_out, fee, 0) };
+        emit!(LiquidityRemoved {
+            pool: ctx.accounts.liquidity_pool.key(),
+            user: ctx.accounts.user.key(),
+            amount_a,
+            amount_b,
+            lp_burned: amount,
+            fee_a,
+            fee_b,
+            reserve_a: ctx.accounts.liquidity_pool.reserve_a,
+            reserve_b: ctx.accounts.liquidity_pool.reserve_b,
+            slot,
+        });
+
+        Ok(())
+    }
+
+    // The swap_tokens function will swap amount of token A for token B, or B for A when reverse is set.
+    // It fails if the user would receive less than min_amount_out.
+    // When a referrer token account is passed, referral_fee_bps of the input is sent to it and the rest is swapped.
+    pub fn swap_tokens(
+        ctx: Context<SwapTokens>,
+        amount: u64,
+        reverse: Option<bool>,
+        min_amount_out: u64,
+        referral_fee_bps: u16,
+        expires_at: i64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        LiquidityPool::check_deadline(now, expires_at)?;
+        ctx.accounts.liquidity_pool.update_price_cumulative(now);
+        Observations::record_in(&ctx.accounts.observations, &ctx.accounts.liquidity_pool)?;
+        require!(amount > 0, DneError::ZeroAmount);
+        let bump = ctx.accounts.liquidity_pool.bump;
+        let reverse = reverse.unwrap_or(false);
+        let slot = Clock::get()?.slot;
+        let fee_bps = ctx.accounts.liquidity_pool.current_fee_bps(slot);
+
+        // Depending on the token the user is swapping, we need to transfer the tokens from the user to the pool
+        let (token_in, token_mint_in, token_out, token_mint_out) =
+            ctx.accounts.get_swap_accounts(reverse);
+        let (reserve_in, reserve_out) = ctx.accounts.liquidity_pool.trade_reserves(reverse);
+
+        // The referral fee comes off the top of the input
+        let referral_fee = if ctx.accounts.referrer_token_account.is_some() {
+            quote::referral_fee(amount, referral_fee_bps)?
+        } else {
+            require!(referral_fee_bps == 0, DneError::ReferrerMissing);
+            0
+        };
+        let amount = amount - referral_fee;
+        require!(amount > 0, DneError::ZeroAmount);
 
         msg!("Amount in pool A: {}", ctx.accounts.lp_token_a.amount);
         msg!("Amount in pool B: {}", ctx.accounts.lp_token_b.amount);
         // Calculate amount to transfer for token B
         let amount_b = LiquidityPool::calculate_swap(
-            token_in.amount,
-            token_mint_in_decimals,
-            token_out.amount,
-            token_mint_out_decimals,
-            amount
+            reserve_in,
+            reserve_out,
+            amount,
+            fee_bps,
+        )?;
+        require!(amount_b > 0, DneError::ZeroAmount);
+        require!(amount_b >= min_amount_out, DneError::SlippageExceeded);
+
+        let protocol_fee = quote::protocol_fee(
+            amount,
+            fee_bps,
+            ctx.accounts.liquidity_pool.protocol_fee_bps,
+        )?;
+        ctx.accounts.liquidity_pool.accrue_protocol_fee(reverse, protocol_fee)?;
+
+        let reserves_after = (
+            reserve_in.checked_add(amount - protocol_fee).ok_or(DneError::MathOverflow)?,
+            reserve_out - amount_b,
         );
+        ctx.accounts.liquidity_pool.record_trade(slot, reverse, (reserve_in, reserve_out), reserves_after)?;
+        ctx.accounts.liquidity_pool.set_trade_reserves(reverse, reserves_after);
+        let fee = quote::swap_fee(amount, fee_bps)?;
+        ctx.accounts.liquidity_pool.record_swap(now, reverse, amount, amount_b, fee);
         msg!("Swapping {} from {} for {} from {}", amount,token_in.key() , amount_b, token_out.key());
 
+        // Pay the referrer
+        if referral_fee > 0 {
+            ctx.accounts.transfer_from_user_to_referrer(&token_mint_in, referral_fee)?;
+        }
+
         // Transfer tokens from user to pool
-        ctx.accounts.transfer_from_user_to_pool(&token_mint_in, amount, bump)?;
+        ctx.accounts.transfer_from_user_to_pool(&token_mint_in, amount)?;
 
         // Transfer tokens to user
         ctx.accounts.transfer_from_pool_to_user(&token_mint_out, amount_b, bump)?;
-        // panic!("End of swap");
+
+        emit!(Swapped {
+            pool: ctx.accounts.liquidity_pool.key(),
+            user: ctx.accounts.user.key(),
+            mint_in: token_mint_in,
+            mint_out: token_mint_out,
+            amount_in: amount,
+            amount_out: amount_b,
+            fee,
+            protocol_fee,
+            referral_fee,
+            reserve_a: ctx.accounts.liquidity_pool.reserve_a,
+            reserve_b: ctx.accounts.liquidity_pool.reserve_b,
+            slot,
+        });
+        Ok(())
+    }
+
+    // The swap_tokens_exact_out function will swap for exactly amount_out of token B, or of token A when reverse is set.
+    // The input is the inverse of calculate_swap including the fee, and it fails if it is more than max_amount_in.
+    pub fn swap_tokens_exact_out(ctx: Context<SwapTokens>, amount_out: u64, max_amount_in: u64, reverse: Option<bool>, expires_at: i64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        LiquidityPool::check_deadline(now, expires_at)?;
+        ctx.accounts.liquidity_pool.update_price_cumulative(now);
+        Observations::record_in(&ctx.accounts.observations, &ctx.accounts.liquidity_pool)?;
+        require!(amount_out > 0, DneError::ZeroAmount);
+        let bump = ctx.accounts.liquidity_pool.bump;
+        let reverse = reverse.unwrap_or(false);
+        let slot = Clock::get()?.slot;
+        let fee_bps = ctx.accounts.liquidity_pool.current_fee_bps(slot);
+
+        let (token_in, token_mint_in, token_out, token_mint_out) =
+            ctx.accounts.get_swap_accounts(reverse);
+        let (reserve_in, reserve_out) = ctx.accounts.liquidity_pool.trade_reserves(reverse);
+
+        // Calculate the amount the user has to pay in for the requested output
+        let amount_in = LiquidityPool::calculate_swap_exact_out(
+            reserve_in,
+            reserve_out,
+            amount_out,
+            fee_bps,
+        )?;
+        require!(amount_in <= max_amount_in, DneError::SlippageExceeded);
+
+        let protocol_fee = quote::protocol_fee(
+            amount_in,
+            fee_bps,
+            ctx.accounts.liquidity_pool.protocol_fee_bps,
+        )?;
+        ctx.accounts.liquidity_pool.accrue_protocol_fee(reverse, protocol_fee)?;
+
+        let reserves_after = (
+            reserve_in.checked_add(amount_in - protocol_fee).ok_or(DneError::MathOverflow)?,
+            reserve_out - amount_out,
+        );
+        ctx.accounts.liquidity_pool.record_trade(slot, reverse, (reserve_in, reserve_out), reserves_after)?;
+        ctx.accounts.liquidity_pool.set_trade_reserves(reverse, reserves_after);
+        let fee = quote::swap_fee(amount_in, fee_bps)?;
+        ctx.accounts.liquidity_pool.record_swap(now, reverse, amount_in, amount_out, fee);
+        msg!("Swapping {} from {} for {} from {}", amount_in, token_in.key(), amount_out, token_out.key());
+
+        // Transfer tokens from user to pool
+        ctx.accounts.transfer_from_user_to_pool(&token_mint_in, amount_in)?;
+
+        // Transfer tokens to user
+        ctx.accounts.transfer_from_pool_to_user(&token_mint_out, amount_out, bump)?;
+
+        emit!(Swapped {
+            pool: ctx.accounts.liquidity_pool.key(),
+            user: ctx.accounts.user.key(),
+            mint_in: token_mint_in,
+            mint_out: token_mint_out,
+            amount_in,
+            amount_out,
+            fee,
+            protocol_fee,
+            referral_fee: 0,
+            reserve_a: ctx.accounts.liquidity_pool.reserve_a,
+            reserve_b: ctx.accounts.liquidity_pool.reserve_b,
+            slot,
+        });
         Ok(())
     }
 }
@@ -178,187 +866,1310 @@ pub struct LiquidityPool {
     pub lp_token_b: Pubkey,
     pub lp_token: Pubkey,
     pub owner: Pubkey,
+    // Swap fee in basis points
+    pub fee_bps: u16,
+    // Share of the swap fee that goes to the protocol, in basis points of the fee
+    pub protocol_fee_bps: u16,
+    // Protocol fees sitting in the vaults until they are collected.
+    // They are not part of the reserves, so LPs never get a share of them.
+    pub protocol_fees_a: u64,
+    pub protocol_fees_b: u64,
+    // When set, the swap fee rises above fee_bps with recent price volatility
+    pub dynamic_fee: bool,
+    // Sum of recent price moves in basis points, decayed from volatility_slot
+    pub volatility_bps: u32,
+    pub volatility_slot: u64,
+    // Paused pools only allow withdrawals
+    pub paused: bool,
+    // Owner proposed by propose_owner, until they accept
+    pub pending_owner: Pubkey,
+    // Layout version, bumped by migrate_pool
+    pub version: u8,
+    // Bumps of the pool PDA and its vaults, so they are not searched for on every instruction
+    pub bump: u8,
+    pub vault_a_bump: u8,
+    pub vault_b_bump: u8,
+    // Reserves the pool prices against. Tokens sent straight to a vault are not part of them
+    // until someone calls sync, and skim sends them back out.
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    // Sums over time of the price of token A in token B and of token B in token A, scaled by quote::PRICE_SCALE.
+    // They wrap on overflow, see quote::accumulate_price.
+    pub price_a_cumulative: u128,
+    pub price_b_cumulative: u128,
+    // Unix timestamp of the last update to the cumulative prices, zero until the first one
+    pub price_timestamp: i64,
+    // Swap totals since the pool was created, or since version 3 for older pools.
+    // A swap adds its input to the volume of the input token and its output to the other one.
+    // Fees are the whole swap fee, LP and protocol share, in the input token.
+    pub volume_a: u128,
+    pub volume_b: u128,
+    pub fees_a: u128,
+    pub fees_b: u128,
+    pub swap_count: u64,
+    // Unix timestamp of the last swap, zero until the first one
+    pub last_trade_timestamp: i64,
+    // Bump of the account holding the locked LP tokens
+    pub locked_bump: u8,
+    // Room for new fields, so they can be added without reallocating every pool
+    pub reserved: [u8; 4],
+}
+
+// Program-wide settings, stored in a single PDA.
+#[account]
+#[derive(Default)]
+pub struct GlobalConfig {
+    // Only the admin can update the config
+    pub admin: Pubkey,
+    // Admin proposed by propose_admin, until they accept
+    pub pending_admin: Pubkey,
+    // Wallet that receives the protocol fees
+    pub treasury: Pubkey,
+    // Fee of new pools that do not pick a tier
+    pub default_fee_bps: u16,
+    // Fees new pools are allowed to pick, in basis points
+    pub fee_tiers: Vec<u16>,
+}
+
+impl GlobalConfig {
+    pub const MAX_FEE_TIERS: usize = 8;
+    pub const SPACE: usize = 8 + 32 + 32 + 32 + 2 + 4 + (2 * Self::MAX_FEE_TIERS);
+
+    // The default fee has to be one of the tiers, so every new pool ends up on a tier.
+    fn set_fee_tiers(&mut self, default_fee_bps: u16, fee_tiers: Vec<u16>) -> Result<()> {
+        require!(fee_tiers.len() <= Self::MAX_FEE_TIERS, DneError::TooManyFeeTiers);
+        require!(fee_tiers.iter().all(|&fee| fee <= quote::MAX_FEE_BPS), DneError::FeeTooHigh);
+        require!(fee_tiers.contains(&default_fee_bps), DneError::FeeTierNotAllowed);
+        self.default_fee_bps = default_fee_bps;
+        self.fee_tiers = fee_tiers;
+        Ok(())
+    }
+
+    // The fee for a new pool: the requested tier, or the default fee when none is given.
+    fn pool_fee_bps(&self, fee_bps: Option<u16>) -> Result<u16> {
+        match fee_bps {
+            Some(fee_bps) => {
+                self.check_fee_tier(fee_bps)?;
+                Ok(fee_bps)
+            }
+            None => Ok(self.default_fee_bps),
+        }
+    }
+
+    // Pools can only charge one of the tiers, both at creation and when the owner changes the fee.
+    fn check_fee_tier(&self, fee_bps: u16) -> Result<()> {
+        require!(self.fee_tiers.contains(&fee_bps), DneError::FeeTierNotAllowed);
+        Ok(())
+    }
+}
+
+// Every pool the program has created, in creation order, so clients can list them all with one account read.
+// The account holds this header and then one PoolEntry per pool, and grows by one entry each time a pool is created.
+// Entries are written in place, so the list never has to fit on the heap. Accounts top out at 10 MiB,
+// which leaves room for about 100,000 pools.
+#[account(zero_copy)]
+pub struct PoolRegistry {
+    pub pool_count: u64,
+}
+
+impl PoolRegistry {
+    const HEADER_SPACE: usize = 8 + std::mem::size_of::<PoolRegistry>();
+
+    pub fn space(len: usize) -> usize {
+        Self::HEADER_SPACE + (len * std::mem::size_of::<PoolEntry>())
+    }
+
+    // Splits the data of a registry account into its header and its entries
+    fn split_mut(data: &mut [u8]) -> Result<(&mut PoolRegistry, &mut [PoolEntry])> {
+        let data = data.get_mut(8..).ok_or(DneError::InvalidPoolAccount)?;
+        require!(data.len() >= Self::HEADER_SPACE - 8, DneError::InvalidPoolAccount);
+        let (header, entries) = data.split_at_mut(Self::HEADER_SPACE - 8);
+        let header = bytemuck::try_from_bytes_mut(header).map_err(|_| DneError::InvalidPoolAccount)?;
+        let len = entries.len() / std::mem::size_of::<PoolEntry>();
+        let entries = entries.get_mut(..len * std::mem::size_of::<PoolEntry>()).ok_or(DneError::InvalidPoolAccount)?;
+        let entries = bytemuck::try_cast_slice_mut(entries).map_err(|_| DneError::InvalidPoolAccount)?;
+        Ok((header, entries))
+    }
+
+    // Adds a pool to a registry account that has been grown to fit it
+    fn push_in(account: &AccountLoader<PoolRegistry>, entry: PoolEntry) -> Result<()> {
+        let info = account.to_account_info();
+        let mut data = info.try_borrow_mut_data()?;
+        let (registry, entries) = Self::split_mut(&mut data)?;
+        registry.push(entries, entry)
+    }
+
+    fn push(&mut self, entries: &mut [PoolEntry], entry: PoolEntry) -> Result<()> {
+        let index = usize::try_from(self.pool_count).map_err(|_| DneError::MathOverflow)?;
+        *entries.get_mut(index).ok_or(DneError::InvalidPoolAccount)? = entry;
+        self.pool_count = self.pool_count.checked_add(1).ok_or(DneError::MathOverflow)?;
+        Ok(())
+    }
+}
+
+#[zero_copy]
+#[derive(Default, Debug, PartialEq)]
+pub struct PoolEntry {
+    pub pool: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+}
+
+// A pool's accumulators at one point in time
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct Observation {
+    pub timestamp: i64,
+    pub price_a_cumulative: u128,
+    pub price_b_cumulative: u128,
+    // Sum over time of the pool liquidity, see LiquidityPool::liquidity
+    pub liquidity_cumulative: u128,
+}
+
+impl Observation {
+    pub const SIZE: usize = 8 + 16 + 16 + 16;
+
+    // The accumulators at timestamp, carried on from previous at the pool's current reserves.
+    fn at(pool: &LiquidityPool, previous: Option<&Observation>, timestamp: i64) -> Observation {
+        let (price_a_cumulative, price_b_cumulative) = pool.price_cumulative_at(timestamp);
+        let liquidity_cumulative = previous.map_or(0, |previous| {
+            let elapsed = timestamp.saturating_sub(previous.timestamp).max(0) as u128;
+            previous.liquidity_cumulative.wrapping_add(pool.liquidity().wrapping_mul(elapsed))
+        });
+        Observation {
+            timestamp,
+            price_a_cumulative,
+            price_b_cumulative,
+            liquidity_cumulative,
+        }
+    }
+
+    // The accumulators at timestamp, between before and after.
+    // The reserves did not change in between, so the accumulators grew linearly.
+    fn interpolate(before: &Observation, after: &Observation, timestamp: i64) -> Observation {
+        // Slots in time order are never this far apart or out of order, but a malformed account should not divide by zero
+        let span = after.timestamp.saturating_sub(before.timestamp).max(1) as u128;
+        let offset = timestamp.saturating_sub(before.timestamp).max(0) as u128;
+        let lerp = |start: u128, end: u128| start.wrapping_add(end.wrapping_sub(start) / span * offset);
+        Observation {
+            timestamp,
+            price_a_cumulative: lerp(before.price_a_cumulative, after.price_a_cumulative),
+            price_b_cumulative: lerp(before.price_b_cumulative, after.price_b_cumulative),
+            liquidity_cumulative: lerp(before.liquidity_cumulative, after.liquidity_cumulative),
+        }
+    }
+}
+
+// Ring buffer of a pool's observations, written by every instruction that changes the reserves.
+// Like the Uniswap v3 oracle, slots added by increase_observation_cardinality are only taken
+// when the newest observation is in the last slot in use, so the slots in use stay in time order.
+// The account holds this header and then the slots, Observation::SIZE Borsh encoded bytes each.
+// Slots are read and written one at a time, so the buffer never has to fit on the heap.
+#[account(zero_copy)]
+pub struct Observations {
+    pub pool: Pubkey,
+    // Slot of the newest observation
+    pub index: u16,
+    // Number of slots written so far, always the first ones
+    pub cardinality: u16,
+    // Bump of the observations PDA, so it is not searched for on every instruction
+    pub bump: u8,
+    pub padding: [u8; 3],
+}
+
+impl Observations {
+    // Most slots the buffer can grow to
+    pub const MAX_CARDINALITY: u16 = 1024;
+    // Most entries one observe call returns, so they fit in the return data
+    pub const MAX_OBSERVE: usize = 16;
+    const HEADER_SPACE: usize = 8 + std::mem::size_of::<Observations>();
+
+    pub fn space(cardinality: usize) -> usize {
+        Self::HEADER_SPACE + (cardinality * Observation::SIZE)
+    }
+
+    // Splits the data of an observations account into its header and its slots.
+    // An account too short to hold the header is rejected rather than read past its end.
+    fn split(data: &[u8]) -> Result<(&Observations, &[u8])> {
+        let data = data.get(8..).ok_or(DneError::InvalidPoolAccount)?;
+        require!(data.len() >= Self::HEADER_SPACE - 8, DneError::InvalidPoolAccount);
+        let (header, slots) = data.split_at(Self::HEADER_SPACE - 8);
+        let header = bytemuck::try_from_bytes(header).map_err(|_| DneError::InvalidPoolAccount)?;
+        Ok((header, slots))
+    }
+
+    fn split_mut(data: &mut [u8]) -> Result<(&mut Observations, &mut [u8])> {
+        let data = data.get_mut(8..).ok_or(DneError::InvalidPoolAccount)?;
+        require!(data.len() >= Self::HEADER_SPACE - 8, DneError::InvalidPoolAccount);
+        let (header, slots) = data.split_at_mut(Self::HEADER_SPACE - 8);
+        let header = bytemuck::try_from_bytes_mut(header).map_err(|_| DneError::InvalidPoolAccount)?;
+        Ok((header, slots))
+    }
+
+    // Records the pool's accumulators in an observations account, see record
+    fn record_in(account: &AccountLoader<Observations>, pool: &LiquidityPool) -> Result<()> {
+        let info = account.to_account_info();
+        let mut data = info.try_borrow_mut_data()?;
+        let (observations, slots) = Self::split_mut(&mut data)?;
+        observations.record(slots, pool)
+    }
+
+    fn initialize(&mut self, pool: Pubkey, bump: u8) {
+        self.pool = pool;
+        self.bump = bump;
+    }
+
+    // Number of slots the account has room for
+    fn capacity(slots: &[u8]) -> usize {
+        slots.len() / Observation::SIZE
+    }
+
+    // Reads a slot. A slot outside the account, from a header that does not match it, is an error.
+    fn slot(slots: &[u8], slot: usize) -> Result<Observation> {
+        let start = slot.checked_mul(Observation::SIZE).ok_or(DneError::InvalidPoolAccount)?;
+        let mut bytes = slots.get(start..start + Observation::SIZE).ok_or(DneError::InvalidPoolAccount)?;
+        Observation::deserialize(&mut bytes).map_err(|_| error!(DneError::InvalidPoolAccount))
+    }
+
+    fn set_slot(slots: &mut [u8], slot: usize, observation: &Observation) -> Result<()> {
+        let start = slot.checked_mul(Observation::SIZE).ok_or(DneError::InvalidPoolAccount)?;
+        let mut bytes = slots.get_mut(start..start + Observation::SIZE).ok_or(DneError::InvalidPoolAccount)?;
+        observation.serialize(&mut bytes).map_err(|_| error!(DneError::InvalidPoolAccount))
+    }
+
+    fn latest(&self, slots: &[u8]) -> Result<Option<Observation>> {
+        if self.cardinality == 0 {
+            return Ok(None);
+        }
+        Self::slot(slots, self.index as usize).map(Some)
+    }
+
+    // The observation n places after the oldest one
+    fn nth_oldest(&self, slots: &[u8], n: u16) -> Result<Observation> {
+        let slot = (self.index as usize + 1 + n as usize)
+            .checked_rem(self.cardinality as usize)
+            .ok_or(DneError::ObservationTooOld)?;
+        Self::slot(slots, slot)
+    }
+
+    // Writes the pool's accumulators, at most once per timestamp.
+    // Called right after LiquidityPool::update_price_cumulative, before the reserves change.
+    fn record(&mut self, slots: &mut [u8], pool: &LiquidityPool) -> Result<()> {
+        let latest = self.latest(slots)?;
+        if latest.is_some_and(|latest| latest.timestamp >= pool.price_timestamp) {
+            return Ok(());
+        }
+        let observation = Observation::at(pool, latest.as_ref(), pool.price_timestamp);
+
+        let next = self.index as usize + 1;
+        if self.cardinality == 0 {
+            self.cardinality = 1;
+            self.index = 0;
+        } else if next == self.cardinality as usize && next < Self::capacity(slots) {
+            // Take the next new slot
+            self.cardinality = u16::try_from(next + 1).map_err(|_| DneError::TooManyObservations)?;
+            self.index = u16::try_from(next).map_err(|_| DneError::TooManyObservations)?;
+        } else {
+            self.index = u16::try_from(next % self.cardinality as usize).map_err(|_| DneError::TooManyObservations)?;
+        }
+        Self::set_slot(slots, self.index as usize, &observation)
+    }
+
+    // The accumulators seconds_ago seconds before now.
+    fn observe(&self, slots: &[u8], pool: &LiquidityPool, now: i64, seconds_ago: u32) -> Result<Observation> {
+        let latest = self.latest(slots)?.ok_or(DneError::ObservationTooOld)?;
+        let target = now.saturating_sub(seconds_ago as i64);
+        if target >= latest.timestamp {
+            return Ok(Observation::at(pool, Some(&latest), target));
+        }
+        require!(target >= self.nth_oldest(slots, 0)?.timestamp, DneError::ObservationTooOld);
+
+        // Binary search for the observations around the target
+        let (mut before, mut after) = (0, self.cardinality - 1);
+        while after - before > 1 {
+            let middle = (before + after) / 2;
+            if self.nth_oldest(slots, middle)?.timestamp <= target {
+                before = middle;
+            } else {
+                after = middle;
+            }
+        }
+        Ok(Observation::interpolate(&self.nth_oldest(slots, before)?, &self.nth_oldest(slots, after)?, target))
+    }
+}
+
+// Events emitted by the program.
+// Indexers can decode these from the IDL instead of parsing log messages.
+// Reserves are the pool's reserves after the instruction.
+#[event]
+pub struct PoolCreated {
+    pub pool: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub lp_mint: Pubkey,
+    pub owner: Pubkey,
+    pub fee_bps: u16,
+    pub slot: u64,
+}
+
+// Single sided deposits swap part of the input through the pool, and fee_a or fee_b is the fee paid on that swap.
+#[event]
+pub struct LiquidityAdded {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_minted: u64,
+    pub fee_a: u64,
+    pub fee_b: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub slot: u64,
+}
+
+// Single token withdrawals swap the other side back into the pool, and fee_a or fee_b is the fee paid on that swap.
+#[event]
+pub struct LiquidityRemoved {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_burned: u64,
+    pub fee_a: u64,
+    pub fee_b: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub slot: u64,
+}
+
+// The fees are in the input token. The protocol fee is part of the fee, the referral fee is paid on top of amount_in.
+#[event]
+pub struct Swapped {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub mint_in: Pubkey,
+    pub mint_out: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee: u64,
+    pub protocol_fee: u64,
+    pub referral_fee: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub slot: u64,
+}
+
+// Results of the view functions, returned as Anchor return data
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PoolReserves {
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub lp_supply: u64,
+}
+
+// Prices are scaled by quote::PRICE_SCALE. price_a is the price of token A in token B.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SpotPrice {
+    pub price_a: u128,
+    pub price_b: u128,
+}
+
+// The fee is in the input token and includes the protocol's share
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SwapQuote {
+    pub amount_out: u64,
+    pub fee_bps: u16,
+    pub fee: u64,
+    pub price_impact_bps: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AddLiquidityQuote {
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_out: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RemoveLiquidityQuote {
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+// Pool parameters the owner can change with update_pool_params
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PoolParams {
+    pub fee_bps: Option<u16>,
+    pub dynamic_fee: Option<bool>,
+}
+
+// Errors returned by the program.
+// Clients can decode these from the IDL instead of parsing log messages.
+#[error_code]
+pub enum DneError {
+    #[msg("Token mint is not part of this pool")]
+    UnknownMint,
+    #[msg("Amount must be greater than zero")]
+    ZeroAmount,
+    #[msg("Pool does not have enough liquidity")]
+    InsufficientLiquidity,
+    #[msg("Math operation overflowed")]
+    MathOverflow,
+    #[msg("Slippage tolerance exceeded")]
+    SlippageExceeded,
+    #[msg("Token mints must be provided in sorted order")]
+    MintsNotSorted,
+    #[msg("Token account mint does not match the expected mint")]
+    MintMismatch,
+    #[msg("Account does not belong to this pool")]
+    InvalidPoolAccount,
+    #[msg("Initial deposit is too small to cover the minimum liquidity")]
+    InitialDepositTooSmall,
+    #[msg("Transaction deadline has passed")]
+    DeadlineExceeded,
+    #[msg("Swap fee is above the maximum")]
+    FeeTooHigh,
+    #[msg("Signer is not the pool owner or program admin")]
+    Unauthorized,
+    #[msg("Referral fee requires a referrer token account")]
+    ReferrerMissing,
+    #[msg("Pool is paused")]
+    PoolPaused,
+    #[msg("Fee is not one of the allowed fee tiers")]
+    FeeTierNotAllowed,
+    #[msg("Too many fee tiers")]
+    TooManyFeeTiers,
+    #[msg("Pool has to be migrated with migrate_pool first")]
+    PoolNotMigrated,
+    #[msg("No observation is old enough for the requested time")]
+    ObservationTooOld,
+    #[msg("Too many observations")]
+    TooManyObservations,
+    #[msg("Program data account does not belong to this program")]
+    InvalidProgramData,
+    #[msg("Token account is not owned by the treasury")]
+    InvalidTreasury,
+}
+
+impl From<quote::QuoteError> for DneError {
+    fn from(error: quote::QuoteError) -> Self {
+        match error {
+            quote::QuoteError::InsufficientLiquidity => DneError::InsufficientLiquidity,
+            quote::QuoteError::MathOverflow => DneError::MathOverflow,
+            quote::QuoteError::SlippageExceeded => DneError::SlippageExceeded,
+            quote::QuoteError::InitialDepositTooSmall => DneError::InitialDepositTooSmall,
+            quote::QuoteError::FeeTooHigh => DneError::FeeTooHigh,
+        }
+    }
+}
+
+impl From<quote::QuoteError> for anchor_lang::error::Error {
+    fn from(error: quote::QuoteError) -> Self {
+        DneError::from(error).into()
+    }
 }
 
 #[derive(Debug)]
 struct LPDepositRequest {
     token_a_balance: u64,
-    token_a_decimals: u8,
     token_b_balance: u64,
-    token_b_decimals: u8,
     lp_token_balance: u64,
-    lp_token_decimals: u8,
     token_a_amount: u64,
     token_b_amount: u64,
 }
 
 impl LiquidityPool {
-    const FEE_PERCENTAGE: f64 = 0.003; // 0.3% fee
+    // LP tokens locked forever on the first deposit
+    const MINIMUM_LIQUIDITY: u64 = quote::MINIMUM_LIQUIDITY;
+    // Decimals of the LP token mint
+    const LP_DECIMALS: u8 = 9;
+    // Current layout version and account size
+    pub const VERSION: u8 = 3;
+    pub const SPACE: usize = 8 + (6 * 32) + 2 + 2 + 8 + 8 + 1 + 4 + 8 + 1 + 32 + 1 + 3 + 8 + 8 + 16 + 16 + 8 + (4 * 16) + 8 + 8 + 5;
+    // Account size of the first pools, which only had the six keys
+    const LEGACY_SPACE: usize = 8 + (6 * 32);
+    // First version with stored bumps and tracked reserves
+    const TRACKED_RESERVES_VERSION: u8 = 2;
+
+    // Brings a pool read from an account of old_len bytes up to the current version.
+    // The fields the old account did not have are zero, so only the ones that need another default are set.
+    fn upgrade(&mut self, old_len: usize, vault_balance_a: u64, vault_balance_b: u64) -> Result<()> {
+        if self.version == Self::VERSION {
+            return Ok(());
+        }
+        if old_len <= Self::LEGACY_SPACE {
+            self.fee_bps = quote::DEFAULT_FEE_BPS;
+        }
+        // Reserves were read from the vaults before version 2, so they start out as the vault balances
+        if self.version < Self::TRACKED_RESERVES_VERSION {
+            (self.reserve_a, self.reserve_b) = self.vault_reserves(vault_balance_a, vault_balance_b)?;
+        }
+        // The swap totals of version 3 start at zero
+        self.version = Self::VERSION;
+        Ok(())
+    }
 
-    fn calculate_lp_amount_to_mint(deposit_request: LPDepositRequest) -> u64 {
-        // Check if the pool has no liquidity
-        if deposit_request.token_a_balance == 0 && deposit_request.token_b_balance == 0 {
-            // Special case for initialization, we mint the LP tokens to the user
-            LiquidityPool::calculate_lp_token_amount_for_initial_deposit(deposit_request)
+    // Finds the bump of a vault. Vaults that have not been through migrate_vaults are seeded by their mint.
+    fn find_vault_bump(seed: &[u8], pool: &Pubkey, mint: &Pubkey, vault: &Pubkey) -> Result<u8> {
+        [pool, mint]
+            .into_iter()
+            .find_map(|key| {
+                let (address, bump) = Pubkey::find_program_address(&[seed, key.as_ref()], &crate::ID);
+                (address == *vault).then_some(bump)
+            })
+            .ok_or(error!(DneError::InvalidPoolAccount))
+    }
+
+    // The math lives in the quote module so off-chain quotes match execution exactly.
+    // These wrappers only turn its errors into program errors.
+    fn calculate_lp_amount_to_mint(deposit_request: LPDepositRequest) -> Result<u64> {
+        Ok(quote::lp_tokens_for_deposit(
+            deposit_request.token_a_balance,
+            deposit_request.token_b_balance,
+            deposit_request.lp_token_balance,
+            deposit_request.token_a_amount,
+            deposit_request.token_b_amount,
+        )?)
+    }
+
+    // Adds the current prices, weighted by the time since the last update, to the cumulative prices.
+    // Called before the reserves change, so each price counts for as long as it held.
+    // Time with an empty pool is skipped, since there is no price.
+    fn update_price_cumulative(&mut self, timestamp: i64) {
+        (self.price_a_cumulative, self.price_b_cumulative) = self.price_cumulative_at(timestamp);
+        self.price_timestamp = self.price_timestamp.max(timestamp);
+    }
+
+    // The cumulative prices at timestamp if the reserves do not change until then.
+    fn price_cumulative_at(&self, timestamp: i64) -> (u128, u128) {
+        let elapsed = timestamp.saturating_sub(self.price_timestamp);
+        if self.price_timestamp > 0 && elapsed > 0 {
+            let prices = (
+                quote::spot_price(self.reserve_a, self.reserve_b),
+                quote::spot_price(self.reserve_b, self.reserve_a),
+            );
+            if let (Ok(price_a), Ok(price_b)) = prices {
+                return (
+                    quote::accumulate_price(self.price_a_cumulative, price_a, elapsed as u64),
+                    quote::accumulate_price(self.price_b_cumulative, price_b, elapsed as u64),
+                );
+            }
+        }
+        (self.price_a_cumulative, self.price_b_cumulative)
+    }
+
+    // Pool liquidity as the geometric mean of the reserves
+    fn liquidity(&self) -> u128 {
+        quote::integer_sqrt(self.reserve_a as u128 * self.reserve_b as u128)
+    }
+
+    // Reserves a deposit is priced against, given the LP token supply.
+    // Until the first LP tokens are minted the pool has no price, so anything in the reserves is left out.
+    // The first deposit then sets the reserves, and what was left out can be skimmed.
+    fn deposit_reserves(&self, lp_supply: u64) -> (u64, u64) {
+        if lp_supply == 0 {
+            (0, 0)
         } else {
-            // Calculate the amount of LP tokens to mint
-            LiquidityPool::calculate_lp_token_amount_for_standard_deposit(deposit_request)
+            (self.reserve_a, self.reserve_b)
         }
     }
 
-    fn calculate_lp_token_amount_for_standard_deposit(deposit_request: LPDepositRequest) -> u64 {
-        // Calculate the amount of LP tokens to mint
+    // Vault balances without the protocol fees that are owed to the treasury.
+    // sync adopts these as the reserves.
+    fn vault_reserves(&self, vault_balance_a: u64, vault_balance_b: u64) -> Result<(u64, u64)> {
+        let reserve_a = vault_balance_a.checked_sub(self.protocol_fees_a).ok_or(DneError::MathOverflow)?;
+        let reserve_b = vault_balance_b.checked_sub(self.protocol_fees_b).ok_or(DneError::MathOverflow)?;
+        Ok((reserve_a, reserve_b))
+    }
 
-        // Total LP amount * min(amount_a / reserve_a, amount_b / reserve_b)
-        (deposit_request.lp_token_balance as f64 * f64::min(
-            deposit_request.token_a_amount as f64 / deposit_request.token_a_balance as f64,
-            deposit_request.token_b_amount as f64 / deposit_request.token_b_balance as f64,
-        )) as u64
+    // Vault balances above the reserves and the protocol fees, which skim sends out.
+    fn excess(&self, vault_balance_a: u64, vault_balance_b: u64) -> Result<(u64, u64)> {
+        let (reserve_a, reserve_b) = self.vault_reserves(vault_balance_a, vault_balance_b)?;
+        let excess_a = reserve_a.checked_sub(self.reserve_a).ok_or(DneError::MathOverflow)?;
+        let excess_b = reserve_b.checked_sub(self.reserve_b).ok_or(DneError::MathOverflow)?;
+        Ok((excess_a, excess_b))
     }
 
-    fn calculate_lp_token_amount_for_initial_deposit(deposit_request: LPDepositRequest) -> u64 {
-        // Calculate the amount of LP tokens to mint
-        // Special case for initialization, we mint the LP tokens to the user
-        // Check if overflow
-        match deposit_request.token_a_amount.checked_mul(deposit_request.token_b_amount) {
-            Some(amount) => (amount as f64).sqrt() as u64,
-            None => {
-                // Overflow, use the decimals to re multiply
-                let adjusted_amount_a = deposit_request.token_a_amount as f64 / 10f64.powi(deposit_request.token_a_decimals as i32);
-                let adjusted_amount_b = deposit_request.token_b_amount as f64 / 10f64.powi(deposit_request.token_b_decimals as i32);
-                // We then need to transfer the decimal places to the LP token amount
-                (((adjusted_amount_a * adjusted_amount_b).sqrt()) * 10f64.powi(deposit_request.lp_token_decimals as i32)) as u64
-            },
+    // Returns the reserves as (in, out) for a trade paying in token A, or token B when in_is_b is set.
+    fn trade_reserves(&self, in_is_b: bool) -> (u64, u64) {
+        if in_is_b {
+            (self.reserve_b, self.reserve_a)
+        } else {
+            (self.reserve_a, self.reserve_b)
         }
     }
 
-    fn calculate_token_amount_to_remove(lp_token_amount: u64, lp_token_supply: u64, token_a_balance: u64, token_b_balance: u64) -> (u64, u64) {
-        // Calculate the amount of tokens to remove
-        let lp_ratio = lp_token_amount as f64 / lp_token_supply as f64;
-        let amount_a = (token_a_balance as f64 * lp_ratio) as u64;
-        let amount_b = (token_b_balance as f64 * lp_ratio) as u64;
-        (amount_a, amount_b)
+    // Stores the (in, out) reserves after a trade paying in token A, or token B when in_is_b is set.
+    fn set_trade_reserves(&mut self, in_is_b: bool, (reserve_in, reserve_out): (u64, u64)) {
+        if in_is_b {
+            (self.reserve_b, self.reserve_a) = (reserve_in, reserve_out);
+        } else {
+            (self.reserve_a, self.reserve_b) = (reserve_in, reserve_out);
+        }
+    }
+
+    // Records the protocol fee on a swap paid in token A, or in token B when paid_in_b is set.
+    fn accrue_protocol_fee(&mut self, paid_in_b: bool, protocol_fee: u64) -> Result<()> {
+        let accrued = if paid_in_b { &mut self.protocol_fees_b } else { &mut self.protocol_fees_a };
+        *accrued = accrued.checked_add(protocol_fee).ok_or(DneError::MathOverflow)?;
+        Ok(())
+    }
+
+    fn set_fee_bps(&mut self, fee_bps: u16) -> Result<()> {
+        require!(fee_bps <= quote::MAX_FEE_BPS, DneError::FeeTooHigh);
+        self.fee_bps = fee_bps;
+        msg!("Swap fee set to {} bps", fee_bps);
+        Ok(())
+    }
+
+    fn set_protocol_fee_bps(&mut self, protocol_fee_bps: u16) -> Result<()> {
+        require!(protocol_fee_bps <= quote::MAX_PROTOCOL_FEE_BPS, DneError::FeeTooHigh);
+        self.protocol_fee_bps = protocol_fee_bps;
+        msg!("Protocol fee set to {} bps of the swap fee", protocol_fee_bps);
+        Ok(())
+    }
+
+    fn set_dynamic_fee(&mut self, dynamic_fee: bool, slot: u64) {
+        self.dynamic_fee = dynamic_fee;
+        // Start from the base fee either way
+        self.volatility_bps = 0;
+        self.volatility_slot = slot;
+        msg!("Dynamic fee set to {}", dynamic_fee);
+    }
+
+    // The swap fee in the given slot. Dynamic fee pools add a share of their decayed volatility to fee_bps.
+    fn current_fee_bps(&self, slot: u64) -> u16 {
+        if !self.dynamic_fee {
+            return self.fee_bps;
+        }
+        let volatility_bps = quote::decay_volatility(self.volatility_bps, slot.saturating_sub(self.volatility_slot));
+        quote::dynamic_fee(self.fee_bps, volatility_bps)
+    }
+
+    // Adds the price move of a trade to the volatility of a dynamic fee pool.
+    // Reserves are given as (in, out), with token B going in when in_is_b is set.
+    // The move is always measured on the price of token A so both directions count the same.
+    fn record_trade(&mut self, slot: u64, in_is_b: bool, reserves_before: (u64, u64), reserves_after: (u64, u64)) -> Result<()> {
+        if !self.dynamic_fee {
+            return Ok(());
+        }
+        let ((a_before, b_before), (a_after, b_after)) = if in_is_b {
+            ((reserves_before.1, reserves_before.0), (reserves_after.1, reserves_after.0))
+        } else {
+            (reserves_before, reserves_after)
+        };
+        let price_before = quote::spot_price(a_before, b_before)?;
+        let price_after = quote::spot_price(a_after, b_after)?;
+
+        let volatility_bps = quote::decay_volatility(self.volatility_bps, slot.saturating_sub(self.volatility_slot));
+        self.volatility_bps = quote::accumulate_volatility(volatility_bps, quote::price_move_bps(price_before, price_after));
+        self.volatility_slot = slot;
+        Ok(())
+    }
+
+    // Adds a swap to the pool's totals, with token B going in when in_is_b is set.
+    // The totals are statistics only, so they saturate instead of failing the swap.
+    fn record_swap(&mut self, timestamp: i64, in_is_b: bool, amount_in: u64, amount_out: u64, fee: u64) {
+        let (volume_in, volume_out, fees_in) = if in_is_b {
+            (&mut self.volume_b, &mut self.volume_a, &mut self.fees_b)
+        } else {
+            (&mut self.volume_a, &mut self.volume_b, &mut self.fees_a)
+        };
+        *volume_in = volume_in.saturating_add(amount_in as u128);
+        *volume_out = volume_out.saturating_add(amount_out as u128);
+        *fees_in = fees_in.saturating_add(fee as u128);
+        self.swap_count = self.swap_count.saturating_add(1);
+        self.last_trade_timestamp = timestamp;
+    }
+
+    // Transactions that land after expires_at, a unix timestamp, are rejected instead of executing at a stale price.
+    fn check_deadline(now: i64, expires_at: i64) -> Result<()> {
+        require!(now <= expires_at, DneError::DeadlineExceeded);
+        Ok(())
+    }
+
+    fn calculate_optimal_deposit(
+        token_a_balance: u64,
+        token_b_balance: u64,
+        amount_a_desired: u64,
+        amount_b_desired: u64,
+        amount_a_min: u64,
+        amount_b_min: u64,
+    ) -> Result<(u64, u64)> {
+        Ok(quote::optimal_deposit(token_a_balance, token_b_balance, amount_a_desired, amount_b_desired, amount_a_min, amount_b_min)?)
+    }
+
+    fn calculate_single_token_removal(
+        lp_token_amount: u64,
+        lp_token_supply: u64,
+        token_balance_out: u64,
+        token_balance_other: u64,
+        fee_bps: u16,
+    ) -> Result<u64> {
+        Ok(quote::single_token_for_burn(lp_token_amount, lp_token_supply, token_balance_out, token_balance_other, fee_bps)?)
+    }
+
+    fn calculate_token_amount_to_remove(lp_token_amount: u64, lp_token_supply: u64, token_a_balance: u64, token_b_balance: u64) -> Option<(u64, u64)> {
+        quote::tokens_for_burn(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance).ok()
     }
 
-    fn calculate_swap(
+    fn calculate_swap(token_balance_in: u64, token_balance_out: u64, amount: u64, fee_bps: u16) -> Result<u64> {
+        Ok(quote::swap_output(token_balance_in, token_balance_out, amount, fee_bps)?)
+    }
+
+    fn calculate_swap_exact_out(token_balance_in: u64, token_balance_out: u64, amount_out: u64, fee_bps: u16) -> Result<u64> {
+        Ok(quote::swap_input(token_balance_in, token_balance_out, amount_out, fee_bps)?)
+    }
+
+    fn calculate_single_sided_deposit(
         token_balance_in: u64,
-        token_in_decimals: u8,
         token_balance_out: u64,
-        token_out_decimals: u8,
+        lp_token_supply: u64,
         amount: u64,
-    ) -> u64 {
-        let fee_percentage = I64F64::from_num(Self::FEE_PERCENTAGE);
-        let amount_in_adjusted = I64F64::from_num(amount) * (I64F64::from_num(1) - fee_percentage);
-        msg!("Amount in adjusted for fee: {}", amount_in_adjusted);
+        fee_bps: u16,
+        protocol_fee_bps: u16,
+    ) -> Result<(u64, u64, u64)> {
+        Ok(quote::single_sided_deposit(token_balance_in, token_balance_out, lp_token_supply, amount, fee_bps, protocol_fee_bps)?)
+    }
+}
 
-        let token_balance_a_adjusted = I64F64::from_num(token_balance_in)
-            / I64F64::from_num(10u64.pow(token_in_decimals as u32));
-        msg!("Token balance token_balance_a_adjusted: {}", token_balance_a_adjusted);
+// The context for the create_pool function.
+// It creates the liquidity pool account, the two vaults, the LP token mint and the locked LP token account.
+#[derive(Accounts)]
+pub struct CreateLiquidityPool<'info> {
+    #[account(
+        init,
+        payer = user,
+        space = LiquidityPool::SPACE,
+        // This enforces that the tokens are provided in sorted order by the client
+        constraint = token_a_mint.key() < token_b_mint.key() @ DneError::MintsNotSorted,
+        seeds = [b"liquidity_pool", token_a_mint.key().as_ref(), token_b_mint.key().as_ref()],
+        bump
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
+    pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
+    // The LP token mint is created here with the pool as its only authority,
+    // so no one can mint LP tokens outside the program
+    #[account(
+        init,
+        payer = user,
+        mint::decimals = LiquidityPool::LP_DECIMALS,
+        mint::authority = liquidity_pool,
+        mint::token_program = token_program,
+        seeds = [b"lp_token", liquidity_pool.key().as_ref()],
+        bump
+    )]
+    pub lp_token: Box<InterfaceAccount<'info, Mint>>,
+    // Need to initialize the token accounts for the PDA
+    // Create the pool's token-account for token A
+    #[account(
+        init,
+        payer = user,
+        token::mint = token_a_mint,
+        token::authority = liquidity_pool,
+        seeds = [b"pool_token_a", liquidity_pool.key().as_ref()],
+        bump
+    )]
+    pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Create the pool's token-account for token B
+    #[account(
+        init,
+        payer = user,
+        token::mint = token_b_mint,
+        token::authority = liquidity_pool,
+        seeds = [b"pool_token_b", liquidity_pool.key().as_ref()],
+        bump
+    )]
+    pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Create the pool's account that holds the permanently locked LP tokens.
+    // Nothing in the program ever transfers out of it.
+    #[account(
+        init,
+        payer = user,
+        token::mint = lp_token,
+        token::authority = liquidity_pool,
+        seeds = [b"locked_liquidity", liquidity_pool.key().as_ref()],
+        bump
+    )]
+    pub locked_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init,
+        payer = user,
+        space = Observations::space(1),
+        seeds = [b"observations", liquidity_pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+    #[account(seeds = [b"global_config"], bump)]
+    pub global_config: Box<Account<'info, GlobalConfig>>,
+    // Grown by one entry for the new pool, paid by the user
+    #[account(
+        mut,
+        seeds = [b"pool_registry"],
+        bump,
+        realloc = PoolRegistry::space(pool_registry.load()?.pool_count as usize + 1),
+        realloc::payer = user,
+        realloc::zero = false
+    )]
+    pub pool_registry: AccountLoader<'info, PoolRegistry>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(address = spl_token_2022::ID)]
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>
+}
 
-        let token_balance_b_adjusted = I64F64::from_num(token_balance_out)
-            / I64F64::from_num(10u64.pow(token_out_decimals as u32));
-        msg!("Token balance token_balance_b_adjusted: {}", token_balance_b_adjusted);
+// The context for the initialize_config function.
+// The config and the registry are singletons, so this can only succeed once.
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = GlobalConfig::SPACE,
+        seeds = [b"global_config"],
+        bump
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(
+        init,
+        payer = admin,
+        space = PoolRegistry::space(0),
+        seeds = [b"pool_registry"],
+        bump
+    )]
+    pub pool_registry: AccountLoader<'info, PoolRegistry>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    // The admin has to be the program's upgrade authority, so no one can take the config over right after a deploy
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @ DneError::InvalidProgramData)]
+    pub program: Program<'info, crate::program::DigitalNomadExchange>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(admin.key()) @ DneError::Unauthorized)]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
 
-        let amount_adjusted = amount_in_adjusted
-            / I64F64::from_num(10u64.pow(token_in_decimals as u32));
-        msg!("Amount adjusted: {}", amount_adjusted);
+// Only the program admin can change the global config
+#[derive(Accounts)]
+pub struct ConfigAdmin<'info> {
+    #[account(mut, seeds = [b"global_config"], bump, has_one = admin @ DneError::Unauthorized)]
+    pub global_config: Account<'info, GlobalConfig>,
+    pub admin: Signer<'info>,
+}
 
-        let product = token_balance_a_adjusted * token_balance_b_adjusted;
-        msg!("Product: {}", product);
+// Only the program admin can change the protocol's share of a pool's fees
+#[derive(Accounts)]
+pub struct ProtocolFeeAdmin<'info> {
+    #[account(seeds = [b"global_config"], bump, has_one = admin @ DneError::Unauthorized)]
+    pub global_config: Box<Account<'info, GlobalConfig>>,
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+}
 
-        let new_balance_a = token_balance_a_adjusted + amount_adjusted;
-        msg!("New balance A: {}", new_balance_a);
+// Only the owner of the pool can change its settings
+#[derive(Accounts)]
+pub struct PoolAdmin<'info> {
+    #[account(mut, has_one = owner @ DneError::Unauthorized)]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    pub owner: Signer<'info>,
+    // Fee changes are checked against the fee tiers
+    #[account(seeds = [b"global_config"], bump)]
+    pub global_config: Box<Account<'info, GlobalConfig>>,
+}
 
-        let new_balance_b = product / new_balance_a;
-        msg!("New balance B: {}", new_balance_b);
+// Only the proposed admin can accept the admin role
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(
+        mut,
+        seeds = [b"global_config"],
+        bump,
+        constraint = global_config.pending_admin == new_admin.key() @ DneError::Unauthorized
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+    pub new_admin: Signer<'info>,
+}
 
-        let amount_out_adjusted = token_balance_b_adjusted - new_balance_b;
-        msg!("Amount out adjusted: {}", amount_out_adjusted);
+// Only the proposed owner can accept the ownership of the pool
+#[derive(Accounts)]
+pub struct AcceptOwner<'info> {
+    #[account(
+        mut,
+        constraint = liquidity_pool.pending_owner == new_owner.key() @ DneError::Unauthorized
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    pub new_owner: Signer<'info>,
+}
 
-        let amount_out = amount_out_adjusted
-            * I64F64::from_num(10u64.pow(token_out_decimals as u32));
-        msg!("Amount out: {}", amount_out);
+// The program admin collects the protocol fees into token accounts owned by the treasury
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    #[account(seeds = [b"global_config"], bump, has_one = admin @ DneError::Unauthorized)]
+    pub global_config: Box<Account<'info, GlobalConfig>>,
+    #[account(
+        mut,
+        constraint = mint_a.key() < mint_b.key() @ DneError::MintsNotSorted,
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
+        seeds = [b"liquidity_pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
+        bump = liquidity_pool.bump,
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, address = liquidity_pool.lp_token_a @ DneError::InvalidPoolAccount)]
+    pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = liquidity_pool.lp_token_b @ DneError::InvalidPoolAccount)]
+    pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = treasury_token_a.mint == mint_a.key() @ DneError::MintMismatch,
+        constraint = treasury_token_a.owner == global_config.treasury @ DneError::InvalidTreasury,
+    )]
+    pub treasury_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = treasury_token_b.mint == mint_b.key() @ DneError::MintMismatch,
+        constraint = treasury_token_b.owner == global_config.treasury @ DneError::InvalidTreasury,
+    )]
+    pub treasury_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub admin: Signer<'info>,
+    #[account(address = spl_token_2022::ID)]
+    pub token_program: Interface<'info, TokenInterface>,
+}
 
-        let final_amount = amount_out.to_num::<u64>().min(token_balance_out);
-        msg!("Final amount: {}", final_amount);
+impl<'info> CollectProtocolFees<'info> {
+    fn transfer_to_treasury_a(&self, bump: u8, amount: u64) -> Result<()> {
+        let cpi_accounts = TransferChecked {
+            from: self.lp_token_a.to_account_info(),
+            mint: self.mint_a.to_account_info(),
+            to: self.treasury_token_a.to_account_info(),
+            authority: self.liquidity_pool.to_account_info(),
+        };
+        // Build the seeds array to match how LiquidityPool PDA was derived
+        let mint_a = self.mint_a.key();
+        let mint_b = self.mint_b.key();
+        let seeds = &[
+            b"liquidity_pool",
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &[bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
 
-        final_amount
+        transfer_checked(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds
+            ),
+            amount,
+            self.mint_a.decimals,
+        )
     }
 
+    fn transfer_to_treasury_b(&self, bump: u8, amount: u64) -> Result<()> {
+        let cpi_accounts = TransferChecked {
+            from: self.lp_token_b.to_account_info(),
+            mint: self.mint_b.to_account_info(),
+            to: self.treasury_token_b.to_account_info(),
+            authority: self.liquidity_pool.to_account_info(),
+        };
+        // Build the seeds array to match how LiquidityPool PDA was derived
+        let mint_a = self.mint_a.key();
+        let mint_b = self.mint_b.key();
+        let seeds = &[
+            b"liquidity_pool",
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &[bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
 
-    fn sort_pubkeys(pubkey_a: Pubkey, pubkey_b: Pubkey) -> (Pubkey, Pubkey) {
-        if pubkey_a < pubkey_b {
-            (pubkey_a, pubkey_b)
-        } else {
-            (pubkey_b, pubkey_a)
-        }
+        transfer_checked(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds
+            ),
+            amount,
+            self.mint_b.decimals,
+        )
     }
 }
 
-// Context for initializing the PDA
+// The context for the migrate_pool function.
 #[derive(Accounts)]
-pub struct CreateLiquidityPoolPDA<'info> {
+pub struct MigratePool<'info> {
+    /// CHECK: pools with an older layout can't be deserialized until they are migrated.
+    /// migrate_pool checks the discriminator, and the owner check keeps out accounts of other programs.
+    #[account(mut, owner = crate::ID @ DneError::InvalidPoolAccount)]
+    pub liquidity_pool: UncheckedAccount<'info>,
+    // The pool's vaults, checked against the pool in migrate_pool, to start tracking the reserves from
+    pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
+    // The pool's LP mint, checked against the pool in migrate_pool
+    pub lp_token: Box<InterfaceAccount<'info, Mint>>,
+    // Created for pools from before it existed, left as is for the others
     #[account(
-            init,
-            payer = user,
-            space = 8 + (6 * 32),
-            // This enforces that the tokens are provided in sorted order by the client
-            constraint = token_a_mint.key() < token_b_mint.key(),
-            seeds = [b"liquidity_pool", token_a_mint.key().as_ref(), token_b_mint.key().as_ref()],
-            bump
+        init_if_needed,
+        payer = payer,
+        token::mint = lp_token,
+        token::authority = liquidity_pool,
+        seeds = [b"locked_liquidity", liquidity_pool.key().as_ref()],
+        bump
     )]
-    pub liquidity_pool: Account<'info, LiquidityPool>,
-    pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
-    pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
+    pub locked_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub payer: Signer<'info>,
+    #[account(address = spl_token_2022::ID)]
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
-// The context for the initialize function.
-// It contains the liquidity pool account, the two token accounts, the LP token mint, the user account, the system program and the rent sysvar.
+// The context for the migrate_vaults function.
+// The old vaults are the ones stored on the pool, the new ones are created at the pool-keyed addresses.
 #[derive(Accounts)]
-pub struct CreateLiquidityPool<'info> {
+pub struct MigrateVaults<'info> {
     #[account(
-        // This enforces that the tokens are provided in sorted order by the client
-        constraint = token_a_mint.key() < token_b_mint.key(),
-        seeds = [b"liquidity_pool", token_a_mint.key().as_ref(), token_b_mint.key().as_ref()],
         mut,
-        bump
+        has_one = owner @ DneError::Unauthorized,
+        constraint = mint_a.key() < mint_b.key() @ DneError::MintsNotSorted,
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
+        seeds = [b"liquidity_pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
+        bump = liquidity_pool.bump,
     )]
     pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
-    pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
-    pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
-    pub lp_token: Box<InterfaceAccount<'info, Mint>>,
-    // Need to initialize the token accounts for the PDA
-    // Create the pool's token-account for token A
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, address = liquidity_pool.lp_token_a @ DneError::InvalidPoolAccount)]
+    pub old_lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = liquidity_pool.lp_token_b @ DneError::InvalidPoolAccount)]
+    pub old_lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(
         init,
-        payer = user,
-        token::mint = token_a_mint,
+        payer = owner,
+        token::mint = mint_a,
         token::authority = liquidity_pool,
-        seeds = [b"pool_token_a", token_a_mint.key().as_ref()],
+        seeds = [b"pool_token_a", liquidity_pool.key().as_ref()],
         bump
     )]
     pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
-
-    // Create the pool's token-account for token B
     #[account(
         init,
-        payer = user,
-        token::mint = token_b_mint,
+        payer = owner,
+        token::mint = mint_b,
         token::authority = liquidity_pool,
-        seeds = [b"pool_token_b", token_b_mint.key().as_ref()],
+        seeds = [b"pool_token_b", liquidity_pool.key().as_ref()],
         bump
     )]
     pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub owner: Signer<'info>,
     #[account(address = spl_token_2022::ID)]
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>
+}
+
+impl<'info> MigrateVaults<'info> {
+    // Moves the whole balance of an old vault into its new vault and closes the old one.
+    fn move_vault(
+        &self,
+        bump: u8,
+        mint: &InterfaceAccount<'info, Mint>,
+        from: &InterfaceAccount<'info, TokenAccount>,
+        to: &InterfaceAccount<'info, TokenAccount>,
+    ) -> Result<()> {
+        // Build the seeds array to match how LiquidityPool PDA was derived
+        let mint_a = self.mint_a.key();
+        let mint_b = self.mint_b.key();
+        let seeds = &[
+            b"liquidity_pool",
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &[bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+
+        if from.amount > 0 {
+            let cpi_accounts = TransferChecked {
+                from: from.to_account_info(),
+                mint: mint.to_account_info(),
+                to: to.to_account_info(),
+                authority: self.liquidity_pool.to_account_info(),
+            };
+            transfer_checked(
+                CpiContext::new_with_signer(
+                    self.token_program.to_account_info(),
+                    cpi_accounts,
+                    signer_seeds
+                ),
+                from.amount,
+                mint.decimals,
+            )?;
+        }
+
+        let cpi_accounts = CloseAccount {
+            account: from.to_account_info(),
+            destination: self.owner.to_account_info(),
+            authority: self.liquidity_pool.to_account_info(),
+        };
+        close_account(CpiContext::new_with_signer(
+            self.token_program.to_account_info(),
+            cpi_accounts,
+            signer_seeds
+        ))
+    }
+}
+
+// The context for the create_observations function.
+#[derive(Accounts)]
+pub struct CreateObservations<'info> {
+    #[account(
+        seeds = [b"liquidity_pool", liquidity_pool.token_a.as_ref(), liquidity_pool.token_b.as_ref()],
+        bump = liquidity_pool.bump,
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    #[account(
+        init,
+        payer = payer,
+        space = Observations::space(1),
+        seeds = [b"observations", liquidity_pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+// The context for the increase_observation_cardinality function.
+// The account never shrinks, so asking for fewer slots than it has keeps its size.
+#[derive(Accounts)]
+#[instruction(cardinality: u16)]
+pub struct IncreaseObservationCardinality<'info> {
+    #[account(
+        mut,
+        seeds = [b"observations", observations.load()?.pool.as_ref()],
+        bump = observations.load()?.bump,
+        realloc = Observations::space(cardinality as usize).max(observations.to_account_info().data_len()),
+        realloc::payer = payer,
+        realloc::zero = false
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+// The context for the observe function.
+#[derive(Accounts)]
+pub struct Observe<'info> {
+    #[account(
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
+        seeds = [b"liquidity_pool", liquidity_pool.token_a.as_ref(), liquidity_pool.token_b.as_ref()],
+        bump = liquidity_pool.bump,
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    #[account(seeds = [b"observations", liquidity_pool.key().as_ref()], bump = observations.load()?.bump)]
+    pub observations: AccountLoader<'info, Observations>,
+}
+
+// The context for the view functions.
+#[derive(Accounts)]
+pub struct PoolView<'info> {
+    #[account(
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
+        seeds = [b"liquidity_pool", liquidity_pool.token_a.as_ref(), liquidity_pool.token_b.as_ref()],
+        bump = liquidity_pool.bump,
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    #[account(address = liquidity_pool.lp_token @ DneError::InvalidPoolAccount)]
+    pub lp_token: Box<InterfaceAccount<'info, Mint>>,
+}
+
+// The context for the sync function.
+#[derive(Accounts)]
+pub struct SyncReserves<'info> {
+    #[account(
+        mut,
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
+        seeds = [b"liquidity_pool", liquidity_pool.token_a.as_ref(), liquidity_pool.token_b.as_ref()],
+        bump = liquidity_pool.bump,
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    #[account(address = liquidity_pool.lp_token_a @ DneError::InvalidPoolAccount)]
+    pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = liquidity_pool.lp_token_b @ DneError::InvalidPoolAccount)]
+    pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = liquidity_pool.lp_token @ DneError::InvalidPoolAccount)]
+    pub lp_token: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, seeds = [b"observations", liquidity_pool.key().as_ref()], bump = observations.load()?.bump)]
+    pub observations: AccountLoader<'info, Observations>,
+}
+
+// The context for the skim function.
+// The excess goes to to_token_a and to_token_b, which can be any accounts of the pool's mints.
+#[derive(Accounts)]
+pub struct Skim<'info> {
+    #[account(
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
+        seeds = [b"liquidity_pool", liquidity_pool.token_a.as_ref(), liquidity_pool.token_b.as_ref()],
+        bump = liquidity_pool.bump,
+    )]
+    pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
+    #[account(address = liquidity_pool.token_a @ DneError::InvalidPoolAccount)]
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+    #[account(address = liquidity_pool.token_b @ DneError::InvalidPoolAccount)]
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, address = liquidity_pool.lp_token_a @ DneError::InvalidPoolAccount)]
+    pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = liquidity_pool.lp_token_b @ DneError::InvalidPoolAccount)]
+    pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, constraint = to_token_a.mint == mint_a.key() @ DneError::MintMismatch)]
+    pub to_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, constraint = to_token_b.mint == mint_b.key() @ DneError::MintMismatch)]
+    pub to_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = spl_token_2022::ID)]
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> Skim<'info> {
+    fn transfer_excess(
+        &self,
+        from: &InterfaceAccount<'info, TokenAccount>,
+        mint: &InterfaceAccount<'info, Mint>,
+        to: &InterfaceAccount<'info, TokenAccount>,
+        amount: u64,
+    ) -> Result<()> {
+        let cpi_accounts = TransferChecked {
+            from: from.to_account_info(),
+            mint: mint.to_account_info(),
+            to: to.to_account_info(),
+            authority: self.liquidity_pool.to_account_info(),
+        };
+        // Build the seeds array to match how LiquidityPool PDA was derived
+        let seeds = &[
+            b"liquidity_pool",
+            self.liquidity_pool.token_a.as_ref(),
+            self.liquidity_pool.token_b.as_ref(),
+            &[self.liquidity_pool.bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+
+        transfer_checked(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds
+            ),
+            amount,
+            mint.decimals,
+        )
+    }
 }
 
 // The context for the add_liquidity function.
@@ -366,37 +2177,43 @@ pub struct CreateLiquidityPool<'info> {
 pub struct AddLiquidity<'info> {
     #[account(
         mut,
-        constraint = mint_a.key() < mint_b.key(),
+        constraint = mint_a.key() < mint_b.key() @ DneError::MintsNotSorted,
+        constraint = !liquidity_pool.paused @ DneError::PoolPaused,
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
         seeds = [b"liquidity_pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump,
+        bump = liquidity_pool.bump,
     )]
     pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
     pub mint_a: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut, constraint = user_token_a.mint == mint_a.key())]
+    #[account(mut, constraint = user_token_a.mint == mint_a.key() @ DneError::MintMismatch)]
     pub user_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
     pub mint_b: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut, constraint = user_token_b.mint == mint_b.key())]
+    #[account(mut, constraint = user_token_b.mint == mint_b.key() @ DneError::MintMismatch)]
     pub user_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(
         mut,
-        token::mint = mint_a,
-        token::authority = liquidity_pool,
-        seeds = [b"pool_token_a", mint_a.key().as_ref()],
-        bump
+        address = liquidity_pool.lp_token_a @ DneError::InvalidPoolAccount
     )]
     pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(
         mut,
-        token::mint = mint_b,
-        token::authority = liquidity_pool,
-        seeds = [b"pool_token_b", mint_b.key().as_ref()],
-        bump
+        address = liquidity_pool.lp_token_b @ DneError::InvalidPoolAccount
     )]
     pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
-    #[account(mut)]
+    #[account(mut, address = liquidity_pool.lp_token @ DneError::InvalidPoolAccount)]
     pub lp_token: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut)]
+    #[account(mut, constraint = user_lp_token_account.mint == lp_token.key() @ DneError::MintMismatch)]
     pub user_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        token::mint = lp_token,
+        token::authority = liquidity_pool,
+        seeds = [b"locked_liquidity", liquidity_pool.key().as_ref()],
+        bump = liquidity_pool.locked_bump
+    )]
+    pub locked_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"observations", liquidity_pool.key().as_ref()], bump = observations.load()?.bump)]
+    pub observations: AccountLoader<'info, Observations>,
     #[account(mut, signer)]
     pub user: Signer<'info>,
     #[account(address = spl_token_2022::ID)]
@@ -409,34 +2226,66 @@ pub struct AddLiquidity<'info> {
 // The function will transfer the token A and B from the user to the pool.
 // It will mint LP tokens to the user.
 impl<'info> AddLiquidity<'info> {
+    fn mint_lp_tokens(&self, bump: u8, to: AccountInfo<'info>, amount: u64) -> Result<()> {
+        // Create Mint LP transaction with the liquidity pool PDA as the authority.
+        let cpi_accounts = MintTo {
+            mint: self.lp_token.to_account_info(),
+            to,
+            authority: self.liquidity_pool.to_account_info(), // LP PDA
+        };
+
+        let mint_a = self.mint_a.key();
+        let mint_b = self.mint_b.key();
+        let seeds = &[
+            b"liquidity_pool",
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &[bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+
+        mint_to(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds
+            ),
+            amount
+        )
+    }
+
     fn transfer_to_pool_a(&self, amount: u64) -> Result<()> {
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: self.user_token_a.to_account_info(),
+            mint: self.mint_a.to_account_info(),
             to: self.lp_token_a.to_account_info(),
             authority: self.user.to_account_info(),
         };
 
-        transfer(
+        transfer_checked(
             CpiContext::new(
                 self.token_program.to_account_info(),
                 cpi_accounts,
             ),
-            amount
+            amount,
+            self.mint_a.decimals,
         )
     }
 
     fn transfer_to_pool_b(&self, amount: u64) -> Result<()> {
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: self.user_token_b.to_account_info(),
+            mint: self.mint_b.to_account_info(),
             to: self.lp_token_b.to_account_info(),
             authority: self.user.to_account_info(),
         };
-        transfer(
+        transfer_checked(
             CpiContext::new(
                 self.token_program.to_account_info(),
                 cpi_accounts,
             ),
-            amount
+            amount,
+            self.mint_b.decimals,
         )
     }
 }
@@ -446,37 +2295,34 @@ impl<'info> AddLiquidity<'info> {
 pub struct RemoveLiquidity<'info> {
     #[account(
         mut,
-        constraint = mint_a.key() < mint_b.key(),
+        constraint = mint_a.key() < mint_b.key() @ DneError::MintsNotSorted,
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
         seeds = [b"liquidity_pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump,
+        bump = liquidity_pool.bump,
     )]
     pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
     pub mint_a: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut, constraint = user_token_a.mint == mint_a.key())]
+    #[account(mut, constraint = user_token_a.mint == mint_a.key() @ DneError::MintMismatch)]
     pub user_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
     pub mint_b: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut, constraint = user_token_b.mint == mint_b.key())]
+    #[account(mut, constraint = user_token_b.mint == mint_b.key() @ DneError::MintMismatch)]
     pub user_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(
         mut,
-        token::mint = mint_a,
-        token::authority = liquidity_pool,
-        seeds = [b"pool_token_a", mint_a.key().as_ref()],
-        bump
+        address = liquidity_pool.lp_token_a @ DneError::InvalidPoolAccount
     )]
     pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(
         mut,
-        token::mint = mint_b,
-        token::authority = liquidity_pool,
-        seeds = [b"pool_token_b", mint_b.key().as_ref()],
-        bump
+        address = liquidity_pool.lp_token_b @ DneError::InvalidPoolAccount
     )]
     pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
-    #[account(mut)]
+    #[account(mut, address = liquidity_pool.lp_token @ DneError::InvalidPoolAccount)]
     pub lp_token: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut)]
+    #[account(mut, constraint = user_lp_token_account.mint == lp_token.key() @ DneError::MintMismatch)]
     pub user_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"observations", liquidity_pool.key().as_ref()], bump = observations.load()?.bump)]
+    pub observations: AccountLoader<'info, Observations>,
     #[account(mut, signer)]
     pub user: Signer<'info>,
     #[account(address = spl_token_2022::ID)]
@@ -490,8 +2336,9 @@ pub struct RemoveLiquidity<'info> {
 // It will transfer token A and B to the user proportional to the pools reserves.
 impl<'info>RemoveLiquidity<'info> {
     fn transfer_from_pool_a(&self, bump:u8, amount: u64) -> Result<()> {
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: self.lp_token_a.to_account_info(),
+            mint: self.mint_a.to_account_info(),
             to: self.user_token_a.to_account_info(),
             authority: self.liquidity_pool.to_account_info(),
         };
@@ -506,19 +2353,21 @@ impl<'info>RemoveLiquidity<'info> {
         ];
         let signer_seeds = &[&seeds[..]];
 
-        transfer(
+        transfer_checked(
             CpiContext::new_with_signer(
                 self.token_program.to_account_info(),
                 cpi_accounts,
                 signer_seeds
             ),
-            amount
+            amount,
+            self.mint_a.decimals,
         )
     }
 
     fn transfer_from_pool_b(&self, bump:u8, amount: u64) -> Result<()> {
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: self.lp_token_b.to_account_info(),
+            mint: self.mint_b.to_account_info(),
             to: self.user_token_b.to_account_info(),
             authority: self.liquidity_pool.to_account_info()
         };
@@ -534,13 +2383,14 @@ impl<'info>RemoveLiquidity<'info> {
         ];
         let signer_seeds = &[&seeds[..]];
 
-        transfer(
+        transfer_checked(
             CpiContext::new_with_signer(
                 self.token_program.to_account_info(),
                 cpi_accounts,
                 signer_seeds
             ),
-            amount
+            amount,
+            self.mint_b.decimals,
         )
     }
 
@@ -579,23 +2429,38 @@ impl<'info>RemoveLiquidity<'info> {
 pub struct SwapTokens<'info> {
     #[account(
         mut,
-        constraint = mint_a.key() < mint_b.key(),
+        constraint = mint_a.key() < mint_b.key() @ DneError::MintsNotSorted,
+        constraint = !liquidity_pool.paused @ DneError::PoolPaused,
+        constraint = liquidity_pool.version == LiquidityPool::VERSION @ DneError::PoolNotMigrated,
         seeds = [b"liquidity_pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump,
+        bump = liquidity_pool.bump,
     )]
     pub liquidity_pool: Box<Account<'info, LiquidityPool>>,
     pub mint_a: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut, constraint = user_token_a.mint == mint_a.key())]
+    #[account(mut, constraint = user_token_a.mint == mint_a.key() @ DneError::MintMismatch)]
     pub user_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
     pub mint_b: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut, constraint = user_token_b.mint == mint_b.key())]
+    #[account(mut, constraint = user_token_b.mint == mint_b.key() @ DneError::MintMismatch)]
     pub user_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
-    #[account(mut, constraint = lp_token_a.mint == mint_a.key())]
+    #[account(
+        mut,
+        address = liquidity_pool.lp_token_a @ DneError::InvalidPoolAccount,
+        constraint = lp_token_a.mint == mint_a.key() @ DneError::MintMismatch
+    )]
     pub lp_token_a: Box<InterfaceAccount<'info, TokenAccount>>,
-    #[account(mut, constraint = lp_token_b.mint == mint_b.key())]
+    #[account(
+        mut,
+        address = liquidity_pool.lp_token_b @ DneError::InvalidPoolAccount,
+        constraint = lp_token_b.mint == mint_b.key() @ DneError::MintMismatch
+    )]
     pub lp_token_b: Box<InterfaceAccount<'info, TokenAccount>>,
-    #[account(mut)]
+    #[account(address = liquidity_pool.lp_token @ DneError::InvalidPoolAccount)]
     pub lp_token: Box<InterfaceAccount<'info, Mint>>,
+    // Receives the referral fee of swap_tokens, in the input token
+    #[account(mut)]
+    pub referrer_token_account: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    #[account(mut, seeds = [b"observations", liquidity_pool.key().as_ref()], bump = observations.load()?.bump)]
+    pub observations: AccountLoader<'info, Observations>,
     #[account(mut, signer)]
     pub user: Signer<'info>,
     #[account(address = spl_token_2022::ID)]
@@ -604,26 +2469,52 @@ pub struct SwapTokens<'info> {
 }
 
 
-impl<'info>SwapTokens<'info> {
-    fn transfer_from_user_to_pool(&self, token_mint: &Pubkey, amount: u64, bump:u8) -> Result<()> {
+impl<'info>SwapTokens<'info> {
+    fn transfer_from_user_to_referrer(&self, token_mint: &Pubkey, amount: u64) -> Result<()> {
+        let (user_account, _, mint, decimals) = self.get_matching_accounts(token_mint)?;
+        let referrer_account = self.referrer_token_account.as_ref().ok_or(DneError::ReferrerMissing)?;
+        require!(referrer_account.mint == *token_mint, DneError::MintMismatch);
+
+        msg!("Transfering {} from user {} to referrer {}", amount, user_account.key(), referrer_account.key());
+
+        let cpi_accounts = TransferChecked {
+            from: user_account,
+            mint,
+            to: referrer_account.to_account_info(),
+            authority: self.user.to_account_info(),
+        };
+
+        transfer_checked(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                cpi_accounts
+            ),
+            amount,
+            decimals,
+        )
+    }
+
+    fn transfer_from_user_to_pool(&self, token_mint: &Pubkey, amount: u64) -> Result<()> {
 
         msg!("Transferring tokens from user to pool");
-        let (user_account, lp_account) = self.get_matching_accounts(token_mint);
+        let (user_account, lp_account, mint, decimals) = self.get_matching_accounts(token_mint)?;
 
         msg!("Transfering {} from user {} to pool {}", amount, user_account.key(), lp_account.key());
 
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: user_account,
+            mint,
             to: lp_account,
             authority: self.user.to_account_info(),
         };
 
-        transfer(
+        transfer_checked(
             CpiContext::new(
                 self.token_program.to_account_info(),
                 cpi_accounts
             ),
-            amount
+            amount,
+            decimals,
         )?;
         msg!("Transfer from user to pool successful");
         Ok(())
@@ -637,12 +2528,13 @@ impl<'info>SwapTokens<'info> {
     ) -> Result<()> {
 
         // Determine which token the user is swapping to
-        let (user_account, lp_account) = self.get_matching_accounts(token_mint);
+        let (user_account, lp_account, mint, decimals) = self.get_matching_accounts(token_mint)?;
 
         msg!("Transfering {} from pool {} to user {}", amount, user_account.key(), lp_account.key());
 
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: lp_account,
+            mint,
             to: user_account,
             // This field means “the address that must sign the transfer”
             authority: self.liquidity_pool.to_account_info(),
@@ -660,26 +2552,39 @@ impl<'info>SwapTokens<'info> {
 
         let signer_seeds = &[&seeds[..]];
 
-        transfer(
+        transfer_checked(
             CpiContext::new_with_signer(
                 self.token_program.to_account_info(),
                 cpi_accounts,
                 signer_seeds
             ),
-            amount
+            amount,
+            decimals,
         )
     }
 
-    fn get_matching_accounts(&self, token_mint: &Pubkey) -> (AccountInfo<'info>, AccountInfo<'info>) {
+    // Returns the pool token accounts and mints as (in, mint_in, out, mint_out) for the swap direction.
+    fn get_swap_accounts(&self, reverse: bool) -> (
+        Box<InterfaceAccount<'info, TokenAccount>>, Pubkey,
+        Box<InterfaceAccount<'info, TokenAccount>>, Pubkey,
+    ) {
+        if reverse {
+            (self.lp_token_b.clone(), self.mint_b.key(), self.lp_token_a.clone(), self.mint_a.key())
+        } else {
+            (self.lp_token_a.clone(), self.mint_a.key(), self.lp_token_b.clone(), self.mint_b.key())
+        }
+    }
+
+    fn get_matching_accounts(&self, token_mint: &Pubkey) -> Result<(AccountInfo<'info>, AccountInfo<'info>, AccountInfo<'info>, u8)> {
         // Here we get the mint of the two tokens, and we check which one the user is trying to swap
         if token_mint == &self.mint_a.key() {
             // If the user is trying to swap token A, we transfer from the user to the pool's token A account
-            (self.user_token_a.to_account_info(), self.lp_token_a.to_account_info())
+            Ok((self.user_token_a.to_account_info(), self.lp_token_a.to_account_info(), self.mint_a.to_account_info(), self.mint_a.decimals))
         } else if token_mint == &self.mint_b.key() {
             // Otherwise, we transfer from the user to the pool's token B account
-            (self.user_token_b.to_account_info(), self.lp_token_b.to_account_info())
+            Ok((self.user_token_b.to_account_info(), self.lp_token_b.to_account_info(), self.mint_b.to_account_info(), self.mint_b.decimals))
         } else {
-            panic!("Token not in pool!");
+            err!(DneError::UnknownMint)
         }
     }
 }
@@ -692,131 +2597,273 @@ mod tests {
     fn test_calculate_lp_token_amount_for_initial_deposit() {
         let deposit_request = LPDepositRequest {
             token_a_balance: 0,
-            token_a_decimals: 9,
             token_b_balance: 0,
-            token_b_decimals: 9,
             lp_token_balance: 0,
-            lp_token_decimals: 9,
+            token_a_amount: 2000,
+            token_b_amount: 2000,
+        };
+        // sqrt(2000 * 2000) = 2000, minus the 1000 locked LP tokens
+        let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
+        assert_eq!(amount_to_mint.unwrap(), 1000, "Initial deposit should mint 1000 LP tokens to the user");
+    }
+
+    #[test]
+    fn test_calculate_lp_token_amount_for_initial_deposit_below_minimum_liquidity() {
+        let deposit_request = LPDepositRequest {
+            token_a_balance: 0,
+            token_b_balance: 0,
+            lp_token_balance: 0,
             token_a_amount: 1000,
             token_b_amount: 1000,
         };
+        // sqrt(1000 * 1000) = 1000, which would all be locked
+        let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
+        assert_eq!(amount_to_mint.unwrap_err(), error!(DneError::InitialDepositTooSmall), "Initial deposit should not be fully locked");
+    }
+
+    #[test]
+    fn test_calculate_lp_token_amount_for_initial_deposit_rounds_down() {
+        // sqrt(2000 * 2001) = 2000.4999... which must floor to 2000, minus the 1000 locked LP tokens
+        let deposit_request = LPDepositRequest {
+            token_a_balance: 0,
+            token_b_balance: 0,
+            lp_token_balance: 0,
+            token_a_amount: 2000,
+            token_b_amount: 2001,
+        };
         let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
-        assert_eq!(amount_to_mint, 1000, "Initial deposit should mint 1000 LP tokens");
+        assert_eq!(amount_to_mint.unwrap(), 1000, "Initial deposit should round down to 1000 LP tokens");
     }
 
     #[test]
     fn test_calculate_lp_token_amount_for_standard_deposit() {
         let deposit_request = LPDepositRequest {
             token_a_balance: 1000,
-            token_a_decimals: 9,
             token_b_balance: 1000,
-            token_b_decimals: 9,
             lp_token_balance: 1000,
-            lp_token_decimals: 9,
             token_a_amount: 500,
             token_b_amount: 500,
         };
-        let change_token_a = deposit_request.token_a_amount as f64 / deposit_request.token_a_balance as f64;
-        let change_token_b = deposit_request.token_b_amount as f64 / deposit_request.token_b_balance as f64;
-        let expected_amount = (deposit_request.lp_token_balance as f64 * f64::min(
-            change_token_a,
-            change_token_b,
-        )) as u64;
-        // Should be 1000 * 0.5 = 500
-        assert_eq!(expected_amount, 500, "Standard deposit should mint 500 LP tokens");
+        // Should be 1000 * 500 / 1000 = 500
         let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
 
-        assert_eq!(amount_to_mint, expected_amount, "Standard deposit should mint 500 LP tokens");
+        assert_eq!(amount_to_mint.unwrap(), 500, "Standard deposit should mint 500 LP tokens");
     }
 
     #[test]
     fn test_calculate_lp_token_amount_for_unequal_deposit() {
         let deposit_request = LPDepositRequest {
             token_a_balance: 1000,
-            token_a_decimals: 9,
             token_b_balance: 1000,
-            token_b_decimals: 9,
             lp_token_balance: 1000,
-            lp_token_decimals: 9,
             token_a_amount: 100,
             token_b_amount: 500,
         };
-        let change_token_a = deposit_request.token_a_amount as f64 / deposit_request.token_a_balance as f64;
-        let change_token_b = deposit_request.token_b_amount as f64 / deposit_request.token_b_balance as f64;
-        let expected_amount = (deposit_request.lp_token_balance as f64 * f64::min(
-            change_token_a,
-            change_token_b,
-        )) as u64;
-        // Should be 1000 * 0.1 = 100
-        assert_eq!(expected_amount, 100, "Standard deposit should mint 500 LP tokens");
+        // Should be min(1000 * 100 / 1000, 1000 * 500 / 1000) = 100
+        let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
+
+        assert_eq!(amount_to_mint.unwrap(), 100, "Unequal deposit should mint 100 LP tokens");
+    }
+
+    #[test]
+    fn test_calculate_lp_token_amount_for_standard_deposit_rounds_down() {
+        let deposit_request = LPDepositRequest {
+            token_a_balance: 3,
+            token_b_balance: 3,
+            lp_token_balance: 10,
+            token_a_amount: 1,
+            token_b_amount: 1,
+        };
+        // 10 * 1 / 3 = 3.33... which must floor to 3
         let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
 
-        assert_eq!(amount_to_mint, expected_amount, "Standard deposit should mint 500 LP tokens");
+        assert_eq!(amount_to_mint.unwrap(), 3, "Deposit should round down to 3 LP tokens");
     }
 
     #[test]
     fn test_calculate_lp_token_amount_initial_large_amounts() {
         let deposit_request = LPDepositRequest {
             token_a_balance: 0,
-            token_a_decimals: 9,
             token_b_balance: 0,
-            token_b_decimals: 9,
             lp_token_balance: 1_000_000_000,
-            lp_token_decimals: 9,
             token_a_amount: 500_000_000_000,
             token_b_amount: 500_000_000_000,
         };
-        let expected_amount = (((deposit_request.token_a_amount as f64 / 10f64.powi(deposit_request.token_a_decimals as i32)
-            * deposit_request.token_b_amount as f64 / 10f64.powi(deposit_request.token_b_decimals as i32)).sqrt()) * 10f64.powi(9)) as u64;
         let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
 
-        assert_eq!(amount_to_mint, expected_amount as u64, "Standard deposit should mint 500 LP tokens");
+        assert_eq!(amount_to_mint.unwrap(), 500_000_000_000 - LiquidityPool::MINIMUM_LIQUIDITY, "Initial deposit should mint 500_000_000_000 LP tokens less the locked amount");
+    }
+
+    #[test]
+    fn test_calculate_lp_token_amount_initial_max_amounts() {
+        // u64::MAX * u64::MAX only fits in a u128, and its square root is exactly u64::MAX before the lock
+        let deposit_request = LPDepositRequest {
+            token_a_balance: 0,
+            token_b_balance: 0,
+            lp_token_balance: 0,
+            token_a_amount: u64::MAX,
+            token_b_amount: u64::MAX,
+        };
+        let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
+
+        assert_eq!(amount_to_mint.unwrap(), u64::MAX - LiquidityPool::MINIMUM_LIQUIDITY, "Initial deposit should mint u64::MAX LP tokens less the locked amount");
     }
 
     #[test]
     fn test_calculate_lp_tokens_amount_initial_different_decimals() {
         let deposit_request = LPDepositRequest {
             token_a_balance: 0,
-            token_a_decimals: 6,
             token_b_balance: 0,
-            token_b_decimals: 9,
             lp_token_balance: 1_000_000_000,
-            lp_token_decimals: 9,
             token_a_amount: 500_000_000_000,
-            token_b_amount: 500_000_000_000,
+            token_b_amount: 500_000_000,
         };
-
-        let expected_amount = (((deposit_request.token_a_amount as f64 / 10f64.powi(deposit_request.token_a_decimals as i32)
-            * deposit_request.token_b_amount as f64 / 10f64.powi(deposit_request.token_b_decimals as i32)).sqrt()) * 10f64.powi(9)) as u64;
+        // sqrt(500_000_000_000 * 500_000_000) = 15_811_388_300.84... which floors to 15_811_388_300, minus the locked 1000
         let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
 
-        assert_eq!(amount_to_mint, expected_amount as u64, "Standard deposit should mint 500 LP tokens");
+        assert_eq!(amount_to_mint.unwrap(), 15_811_387_300, "Initial deposit should mint 15_811_387_300 LP tokens");
     }
 
     #[test]
-    fn test_calculate_lp_tokens_amount_different_decimals() {
+    fn test_calculate_lp_tokens_amount_standard_deposit_above_f64_precision() {
+        // These values are above 2^53, where f64 math can no longer represent every integer.
         let deposit_request = LPDepositRequest {
-            token_a_balance: 1000,
-            token_a_decimals: 3,
-            token_b_balance: 1000,
-            token_b_decimals: 9,
-            lp_token_balance: 1000,
-            lp_token_decimals: 9,
-            token_a_amount: 500,
-            token_b_amount: 500,
+            token_a_balance: 9_007_199_254_740_993,
+            token_b_balance: 9_007_199_254_740_993,
+            lp_token_balance: 9_007_199_254_740_993,
+            token_a_amount: 1,
+            token_b_amount: 1,
         };
+        let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
+
+        assert_eq!(amount_to_mint.unwrap(), 1, "Deposit should mint exactly 1 LP token");
+    }
 
-        let change_token_a = deposit_request.token_a_amount as f64 / deposit_request.token_a_balance as f64;
-        let change_token_b = deposit_request.token_b_amount as f64 / deposit_request.token_b_balance as f64;
-        let expected_amount = (deposit_request.lp_token_balance as f64 * f64::min(
-            change_token_a,
-            change_token_b,
-        )) as u64;
-        // Should be 1000 * 0.5 = 500
-        assert_eq!(expected_amount, 500, "Deposit should mint 500 LP tokens");
+    #[test]
+    fn test_calculate_lp_tokens_amount_overflowing_mint() {
+        // Minting more than u64::MAX LP tokens cannot be represented and must fail.
+        let deposit_request = LPDepositRequest {
+            token_a_balance: 1,
+            token_b_balance: 1,
+            lp_token_balance: u64::MAX,
+            token_a_amount: 2,
+            token_b_amount: 2,
+        };
         let amount_to_mint = LiquidityPool::calculate_lp_amount_to_mint(deposit_request);
 
-        assert_eq!(amount_to_mint, expected_amount, "Deposit should mint 500 LP tokens");
+        assert_eq!(amount_to_mint.unwrap_err(), error!(DneError::MathOverflow), "Deposit should not mint more than u64::MAX LP tokens");
+    }
+
+    #[test]
+    fn test_calculate_optimal_deposit_initial() {
+        let amounts = LiquidityPool::calculate_optimal_deposit(0, 0, 1000, 500, 1000, 500).unwrap();
+        assert_eq!(amounts, (1000, 500), "Initial deposit should take both desired amounts");
+    }
+
+    #[test]
+    fn test_calculate_optimal_deposit_excess_b() {
+        // The pool is 2:1, so 100 token A only needs 50 token B
+        let amounts = LiquidityPool::calculate_optimal_deposit(2000, 1000, 100, 80, 0, 0).unwrap();
+        assert_eq!(amounts, (100, 50), "Should only take 50 token B");
+    }
+
+    #[test]
+    fn test_calculate_optimal_deposit_excess_a() {
+        // The pool is 2:1, so 40 token B only needs 80 token A
+        let amounts = LiquidityPool::calculate_optimal_deposit(2000, 1000, 100, 40, 0, 0).unwrap();
+        assert_eq!(amounts, (80, 40), "Should only take 80 token A");
+    }
+
+    #[test]
+    fn test_calculate_optimal_deposit_rounds_down() {
+        // 10 * 1000 / 3000 = 3.33... which floors to 3
+        let amounts = LiquidityPool::calculate_optimal_deposit(3000, 1000, 10, 10, 0, 0).unwrap();
+        assert_eq!(amounts, (10, 3), "Should round the matched side down");
+    }
+
+    #[test]
+    fn test_calculate_optimal_deposit_below_minimum() {
+        let result = LiquidityPool::calculate_optimal_deposit(2000, 1000, 100, 80, 0, 60);
+        assert_eq!(result.unwrap_err(), error!(DneError::SlippageExceeded), "Should fail when token B is below its minimum");
+        let result = LiquidityPool::calculate_optimal_deposit(2000, 1000, 100, 40, 90, 0);
+        assert_eq!(result.unwrap_err(), error!(DneError::SlippageExceeded), "Should fail when token A is below its minimum");
+    }
+
+    #[test]
+    fn test_calculate_optimal_deposit_mints_without_donation() {
+        // Depositing the optimal amounts mints the same LP for both sides, so nothing is donated to the pool
+        let (amount_a, amount_b) = LiquidityPool::calculate_optimal_deposit(2000, 1000, 100, 80, 0, 0).unwrap();
+        let lp_for_a = LiquidityPool::calculate_lp_amount_to_mint(LPDepositRequest {
+            token_a_balance: 2000,
+            token_b_balance: 1000,
+            lp_token_balance: 1414,
+            token_a_amount: amount_a,
+            token_b_amount: amount_b,
+        });
+        assert_eq!(lp_for_a.unwrap(), 70, "Should mint 70 LP tokens");
+        assert_eq!(amount_a * 1414 / 2000, amount_b * 1414 / 1000, "Both sides should be worth the same LP");
+    }
+
+    #[test]
+    fn test_calculate_single_sided_swap_amount() {
+        // sqrt(1000^2 * 1997^2 + 4 * 997 * 1000 * 1000 * 100) = 2_094_353.36... so s = (2_094_353 - 1_997_000) / 1994 = 48.82...
+        let swap_amount = quote::single_sided_swap_amount(1000, 100, quote::DEFAULT_FEE_BPS).unwrap();
+        assert_eq!(swap_amount, 48, "Should swap 48 of the 100 token A");
+    }
+
+    #[test]
+    fn test_calculate_single_sided_swap_amount_large_reserves() {
+        // reserve_in^2 alone overflows a u128 here
+        let swap_amount = quote::single_sided_swap_amount(u64::MAX, u64::MAX, quote::DEFAULT_FEE_BPS).unwrap();
+        assert!(swap_amount < u64::MAX / 2, "Should swap less than half of the deposit");
+        assert!(swap_amount > u64::MAX / 3, "Should swap a large part of a deposit the size of the pool");
+    }
+
+    #[test]
+    fn test_calculate_single_sided_deposit() {
+        let token_balance_a = 1_000_000_000;
+        let token_balance_b = 500_000_000;
+        let lp_token_supply = 707_106_781;
+        let amount = 10_000_000;
+        let (amount_used, amount_to_mint, _) = LiquidityPool::calculate_single_sided_deposit(token_balance_a, token_balance_b, lp_token_supply, amount, quote::DEFAULT_FEE_BPS, 0).unwrap();
+        assert!(amount_used <= amount, "Should not use more than the deposit");
+        // Matching the token B side is rounded down, leaving up to reserve_a / reserve_b units of token A behind
+        assert!(amount - amount_used <= 3, "Should use all but rounding dust of the deposit");
+
+        // Without the fee, depositing 1% more of token A would mint sqrt(1.01) - 1 = 0.4987% more LP.
+        // The fee on the swapped half costs a little of that.
+        assert_eq!(amount_to_mint, 3_521_439, "Should mint 3_521_439 LP tokens");
+        assert!(amount_to_mint < 3_526_738, "Should mint less than a fee free deposit");
+    }
+
+    #[test]
+    fn test_calculate_single_sided_deposit_empty_pool() {
+        let result = LiquidityPool::calculate_single_sided_deposit(0, 0, 0, 1000, quote::DEFAULT_FEE_BPS, 0);
+        assert_eq!(result.unwrap_err(), error!(DneError::InsufficientLiquidity), "Should not zap into an empty pool");
+    }
+
+    #[test]
+    fn test_calculate_single_token_removal() {
+        // 10% of the pool is 100_000_000 A and 50_000_000 B.
+        // The B is swapped into the 900_000_000 A / 450_000_000 B that is left:
+        // 50_000_000 * 997 * 900_000_000 / (450_000_000 * 1000 + 50_000_000 * 997) = 89_756_927 A
+        let amount_out = LiquidityPool::calculate_single_token_removal(100, 1000, 1_000_000_000, 500_000_000, quote::DEFAULT_FEE_BPS).unwrap();
+        assert_eq!(amount_out, 189_756_927, "Should pay out 189_756_927 token A");
+    }
+
+    #[test]
+    fn test_calculate_single_token_removal_whole_pool() {
+        // Nothing is left in the pool to swap the token B against
+        let result = LiquidityPool::calculate_single_token_removal(1000, 1000, 1_000_000_000, 500_000_000, quote::DEFAULT_FEE_BPS);
+        assert_eq!(result.unwrap_err(), error!(DneError::InsufficientLiquidity), "Should not withdraw the whole pool as one token");
+    }
+
+    #[test]
+    fn test_check_deadline() {
+        assert!(LiquidityPool::check_deadline(1_700_000_000, 1_700_000_060).is_ok(), "Should accept a deadline in the future");
+        assert!(LiquidityPool::check_deadline(1_700_000_000, 1_700_000_000).is_ok(), "Should accept a deadline of the current time");
+        let result = LiquidityPool::check_deadline(1_700_000_001, 1_700_000_000);
+        assert_eq!(result.unwrap_err(), error!(DneError::DeadlineExceeded), "Should reject an expired deadline");
     }
 
     #[test]
@@ -825,9 +2872,8 @@ mod tests {
         let lp_token_supply = 1_000;
         let token_a_balance = 1_000;
         let token_b_balance = 1_000;
-        let (amount_a, amount_b) = LiquidityPool::calculate_token_amount_to_remove(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance);
-        assert_eq!(amount_a, 100, "Should withdraw 10 token A");
-        assert_eq!(amount_b, 100, "Should withdraw 10 token B");
+        let amounts = LiquidityPool::calculate_token_amount_to_remove(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance);
+        assert_eq!(amounts, Some((100, 100)), "Should withdraw 100 token A and 100 token B");
 
     }
 
@@ -837,9 +2883,9 @@ mod tests {
         let lp_token_supply = 1_300;
         let token_a_balance = 13_400;
         let token_b_balance = 342;
-        let (amount_a, amount_b) = LiquidityPool::calculate_token_amount_to_remove(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance);
-        assert_eq!(amount_a, 1030, "Should withdraw 100 token A");
-        assert_eq!(amount_b, 26, "Should withdraw 100 token B");
+        // 100 * 13_400 / 1_300 = 1030.76... and 100 * 342 / 1_300 = 26.30... both floor
+        let amounts = LiquidityPool::calculate_token_amount_to_remove(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance);
+        assert_eq!(amounts, Some((1030, 26)), "Should withdraw 1030 token A and 26 token B");
     }
 
     #[test]
@@ -848,9 +2894,25 @@ mod tests {
         let lp_token_supply = 1_000;
         let token_a_balance = 1_000 * 10u64.pow(9);
         let token_b_balance = 1_000* 10u64.pow(9);
-        let (amount_a, amount_b) = LiquidityPool::calculate_token_amount_to_remove(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance);
-        assert_eq!(amount_a, 100 * 10u64.pow(9), "Should withdraw 100 token A");
-        assert_eq!(amount_b, 100 * 10u64.pow(9), "Should withdraw 100 token B");
+        let amounts = LiquidityPool::calculate_token_amount_to_remove(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance);
+        assert_eq!(amounts, Some((100 * 10u64.pow(9), 100 * 10u64.pow(9))), "Should withdraw 100 token A and 100 token B");
+    }
+
+    #[test]
+    fn test_remove_liquidity_withdrawal_above_f64_precision() {
+        let lp_token_amount = 1;
+        let lp_token_supply = 3;
+        let token_a_balance = u64::MAX;
+        let token_b_balance = 9_007_199_254_740_993;
+        // u64::MAX / 3 = 6_148_914_691_236_517_205 and 9_007_199_254_740_993 / 3 = 3_002_399_751_580_331
+        let amounts = LiquidityPool::calculate_token_amount_to_remove(lp_token_amount, lp_token_supply, token_a_balance, token_b_balance);
+        assert_eq!(amounts, Some((6_148_914_691_236_517_205, 3_002_399_751_580_331)), "Should withdraw exact amounts");
+    }
+
+    #[test]
+    fn test_remove_liquidity_empty_supply() {
+        let amounts = LiquidityPool::calculate_token_amount_to_remove(100, 0, 1_000, 1_000);
+        assert_eq!(amounts, None, "Should not withdraw from a pool with no LP supply");
     }
 
     #[test]
@@ -861,7 +2923,7 @@ mod tests {
         let fee_percentage = 0.003;
         let amount_after_fee = amount as f64 * (1.0 - fee_percentage);
         let expected_amount_b = (amount_after_fee * token_balance_b as f64 / (token_balance_a as f64 + amount_after_fee)) as u64;
-        let amount_b = LiquidityPool::calculate_swap(token_balance_a,9, token_balance_b,9, amount);
+        let amount_b = LiquidityPool::calculate_swap(token_balance_a, token_balance_b, amount, quote::DEFAULT_FEE_BPS).unwrap();
         assert_eq!(amount_b, expected_amount_b, "Should swap 90.66 ~round down to 90 token B");
     }
 
@@ -873,7 +2935,8 @@ mod tests {
         let fee_percentage = 0.003;
         let amount_after_fee = amount as f64 * (1.0 - fee_percentage);
         let expected_amount_b = (amount_after_fee * token_balance_b as f64 / (token_balance_a as f64 + amount_after_fee)) as u64;
-        let amount_b = LiquidityPool::calculate_swap(token_balance_a,9, token_balance_b,9, amount);
+        let amount_b = LiquidityPool::calculate_swap(token_balance_a, token_balance_b, amount, quote::DEFAULT_FEE_BPS).unwrap();
+        assert_eq!(amount_b, expected_amount_b, "Should match the constant product formula");
         assert_eq!(amount_b, 35, "Should swap 35.5 ~35 token B");
     }
 
@@ -885,7 +2948,7 @@ mod tests {
         let fee_percentage = 0.003;
         let amount_after_fee = amount as f64 * (1.0 - fee_percentage);
         let expected_amount_b = (amount_after_fee * token_balance_b as f64 / (token_balance_a as f64 + amount_after_fee)) as u64;
-        let amount_b = LiquidityPool::calculate_swap(token_balance_a, 9, token_balance_b, 9, amount);
+        let amount_b = LiquidityPool::calculate_swap(token_balance_a, token_balance_b, amount, quote::DEFAULT_FEE_BPS).unwrap();
         assert_eq!(amount_b, expected_amount_b, "Should swap large number of token B");
     }
 
@@ -897,10 +2960,54 @@ mod tests {
         let fee_percentage = 0.003;
         let amount_after_fee = amount as f64 * (1.0 - fee_percentage);
         let expected_amount_b = (amount_after_fee * token_balance_b as f64 / (token_balance_a as f64 + amount_after_fee)) as u64;
-        let amount_b = LiquidityPool::calculate_swap(token_balance_a, 9, token_balance_b, 9, amount);
+        let amount_b = LiquidityPool::calculate_swap(token_balance_a, token_balance_b, amount, quote::DEFAULT_FEE_BPS).unwrap();
         assert_eq!(amount_b, expected_amount_b, "Should swap speicifc number of token B: 49845");
     }
 
+    #[test]
+    fn test_calculate_token_swap_empty_pool() {
+        let result = LiquidityPool::calculate_swap(0, 1000, 100, quote::DEFAULT_FEE_BPS);
+        assert_eq!(result.unwrap_err(), error!(DneError::InsufficientLiquidity), "Should not swap against an empty pool");
+    }
+
+    #[test]
+    fn test_calculate_token_swap_overflow() {
+        // u64::MAX * 997 * u64::MAX does not fit in a u128
+        let result = LiquidityPool::calculate_swap(1000, u64::MAX, u64::MAX, quote::DEFAULT_FEE_BPS);
+        assert_eq!(result.unwrap_err(), error!(DneError::MathOverflow), "Should return an overflow error instead of panicking");
+    }
+
+    #[test]
+    fn test_calculate_token_swap_exact_out() {
+        let token_balance_a = 1000;
+        let token_balance_b = 1000;
+        let amount_out = 90;
+        // 1000 * 90 * 1000 / (910 * 997) = 99.19... which rounds up to 100
+        let amount_in = LiquidityPool::calculate_swap_exact_out(token_balance_a, token_balance_b, amount_out, quote::DEFAULT_FEE_BPS).unwrap();
+        assert_eq!(amount_in, 100, "Should need 100 token A for 90 token B");
+    }
+
+    #[test]
+    fn test_calculate_token_swap_exact_out_round_trip() {
+        let token_balance_a = 1_000_000_000;
+        let token_balance_b = 500_000_000;
+        for amount_out in [1, 49_845, 1_234_567, 250_000_000, 499_999_999] {
+            let amount_in = LiquidityPool::calculate_swap_exact_out(token_balance_a, token_balance_b, amount_out, quote::DEFAULT_FEE_BPS).unwrap();
+            // Paying the quoted input must return at least the requested output
+            let received = LiquidityPool::calculate_swap(token_balance_a, token_balance_b, amount_in, quote::DEFAULT_FEE_BPS).unwrap();
+            assert!(received >= amount_out, "Should receive at least {} token B, got {}", amount_out, received);
+            // And one less unit of input must not be enough
+            let received = LiquidityPool::calculate_swap(token_balance_a, token_balance_b, amount_in - 1, quote::DEFAULT_FEE_BPS).unwrap();
+            assert!(received < amount_out, "Quoted input for {} token B is not the minimum", amount_out);
+        }
+    }
+
+    #[test]
+    fn test_calculate_token_swap_exact_out_drains_pool() {
+        let result = LiquidityPool::calculate_swap_exact_out(1000, 1000, 1000, quote::DEFAULT_FEE_BPS);
+        assert_eq!(result.unwrap_err(), error!(DneError::InsufficientLiquidity), "Should not swap for the whole reserve");
+    }
+
     #[test]
     fn test_print_bytes_of_address() {
         use std::str::FromStr;
@@ -924,8 +3031,520 @@ mod tests {
         //     pub lp_token_b: Pubkey,
         //     pub lp_token: Pubkey,
         //     pub owner: Pubkey,
+        //     pub fee_bps: u16,
+        //     pub protocol_fee_bps: u16,
+        //     pub protocol_fees_a: u64,
+        //     pub protocol_fees_b: u64,
+        //     pub dynamic_fee: bool,
+        //     pub volatility_bps: u32,
+        //     pub volatility_slot: u64,
+        //     pub paused: bool,
+        //     pub pending_owner: Pubkey,
+        //     pub version: u8,
+        //     pub bump: u8,
+        //     pub vault_a_bump: u8,
+        //     pub vault_b_bump: u8,
+        //     pub reserve_a: u64,
+        //     pub reserve_b: u64,
+        //     pub price_a_cumulative: u128,
+        //     pub price_b_cumulative: u128,
+        //     pub price_timestamp: i64,
+        //     pub volume_a: u128,
+        //     pub volume_b: u128,
+        //     pub fees_a: u128,
+        //     pub fees_b: u128,
+        //     pub swap_count: u64,
+        //     pub last_trade_timestamp: i64,
+        //     pub locked_bump: u8,
+        //     pub reserved: [u8; 4],
         // }
-        // then its size should be 6 * 32 = 192 bytes.
-        assert_eq!(size, 192);
+        // then its fields take 7 * 32 + 2 * 2 + 8 * 8 + 6 * 16 + 4 + 7 + 4 = 403 bytes,
+        // and its size is rounded up to the 16 byte alignment of the u128 fields, 416 bytes.
+        assert_eq!(size, 416);
+
+        // The account stores the fields without padding, after the 8 byte discriminator
+        let serialized = LiquidityPool::default().try_to_vec().unwrap();
+        assert_eq!(serialized.len() + 8, LiquidityPool::SPACE);
+    }
+
+    #[test]
+    fn test_upgrade_legacy_pool() {
+        let legacy_pool = LiquidityPool {
+            token_a: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+            fee_bps: 5,
+            ..Default::default()
+        };
+        // A legacy account only holds the six keys, the rest reads as zero once it is reallocated
+        let mut data = legacy_pool.try_to_vec().unwrap();
+        data.truncate(LiquidityPool::LEGACY_SPACE - 8);
+        data.resize(LiquidityPool::SPACE - 8, 0);
+        let mut liquidity_pool = LiquidityPool::try_from_slice(&data).unwrap();
+
+        liquidity_pool.upgrade(LiquidityPool::LEGACY_SPACE, 1_000, 2_000).unwrap();
+        assert_eq!(liquidity_pool.version, LiquidityPool::VERSION, "Should be on the current version");
+        assert_eq!(liquidity_pool.fee_bps, quote::DEFAULT_FEE_BPS, "Should get the default fee");
+        assert_eq!(liquidity_pool.token_a, legacy_pool.token_a, "Should keep the keys");
+        assert_eq!(liquidity_pool.owner, legacy_pool.owner, "Should keep the owner");
+        assert_eq!((liquidity_pool.reserve_a, liquidity_pool.reserve_b), (1_000, 2_000), "Should start tracking the vault balances");
+    }
+
+    #[test]
+    fn test_upgrade_keeps_fee() {
+        let mut liquidity_pool = LiquidityPool {
+            fee_bps: 5,
+            ..Default::default()
+        };
+        liquidity_pool.upgrade(LiquidityPool::LEGACY_SPACE + 2, 0, 0).unwrap();
+        assert_eq!(liquidity_pool.version, LiquidityPool::VERSION, "Should be on the current version");
+        assert_eq!(liquidity_pool.fee_bps, 5, "Should keep a fee the old layout already had");
+    }
+
+    #[test]
+    fn test_upgrade_version_1_reserves() {
+        let mut liquidity_pool = LiquidityPool {
+            version: 1,
+            fee_bps: 30,
+            protocol_fees_a: 100,
+            protocol_fees_b: 50,
+            ..Default::default()
+        };
+        liquidity_pool.upgrade(LiquidityPool::SPACE, 10_100, 20_050).unwrap();
+        assert_eq!((liquidity_pool.reserve_a, liquidity_pool.reserve_b), (10_000, 20_000), "Should leave the protocol fees out of the reserves");
+        assert_eq!(liquidity_pool.fee_bps, 30, "Should keep the fee");
+
+        // Upgrading a current pool again changes nothing
+        liquidity_pool.upgrade(LiquidityPool::SPACE, 1, 1).unwrap();
+        assert_eq!((liquidity_pool.reserve_a, liquidity_pool.reserve_b), (10_000, 20_000), "Should not touch a current pool");
+    }
+
+    #[test]
+    fn test_upgrade_version_2_keeps_reserves() {
+        let mut liquidity_pool = LiquidityPool {
+            version: 2,
+            reserve_a: 10_000,
+            reserve_b: 20_000,
+            ..Default::default()
+        };
+        // The vaults also hold a donation that was never synced
+        liquidity_pool.upgrade(LiquidityPool::SPACE, 11_000, 20_000).unwrap();
+        assert_eq!(liquidity_pool.version, LiquidityPool::VERSION, "Should be on the current version");
+        assert_eq!((liquidity_pool.reserve_a, liquidity_pool.reserve_b), (10_000, 20_000), "Should keep the tracked reserves");
+        assert_eq!((liquidity_pool.swap_count, liquidity_pool.volume_a), (0, 0), "Should start the swap totals at zero");
+    }
+
+    #[test]
+    fn test_record_swap() {
+        let mut liquidity_pool = LiquidityPool::default();
+        liquidity_pool.record_swap(100, false, 1_000, 490, 3);
+        liquidity_pool.record_swap(200, true, 500, 990, 1);
+        assert_eq!((liquidity_pool.volume_a, liquidity_pool.volume_b), (1_990, 990), "Should count both sides of each swap");
+        assert_eq!((liquidity_pool.fees_a, liquidity_pool.fees_b), (3, 1), "Should count the fee in the input token");
+        assert_eq!(liquidity_pool.swap_count, 2, "Should count the swaps");
+        assert_eq!(liquidity_pool.last_trade_timestamp, 200, "Should keep the time of the last swap");
+
+        liquidity_pool.volume_a = u128::MAX;
+        liquidity_pool.record_swap(300, false, 1, 1, 0);
+        assert_eq!(liquidity_pool.volume_a, u128::MAX, "Should saturate instead of failing the swap");
+    }
+
+    #[test]
+    fn test_deposit_reserves() {
+        // Reserves left by a migration or a donation before the first deposit
+        let liquidity_pool = LiquidityPool {
+            reserve_a: 1,
+            reserve_b: 0,
+            ..Default::default()
+        };
+        assert_eq!(liquidity_pool.deposit_reserves(0), (0, 0), "A pool without LP tokens should price like an empty pool");
+        let (reserve_a, reserve_b) = liquidity_pool.deposit_reserves(0);
+        let deposit = LiquidityPool::calculate_optimal_deposit(reserve_a, reserve_b, 1_000_000, 1_000_000, 0, 0);
+        assert_eq!(deposit.unwrap(), (1_000_000, 1_000_000), "The first deposit should set the price");
+        assert_eq!(liquidity_pool.deposit_reserves(1_000), (1, 0), "Should use the reserves once there are LP tokens");
+    }
+
+    #[test]
+    fn test_trade_reserves() {
+        let mut liquidity_pool = LiquidityPool {
+            reserve_a: 1_000,
+            reserve_b: 2_000,
+            ..Default::default()
+        };
+        assert_eq!(liquidity_pool.trade_reserves(false), (1_000, 2_000), "Token A in should read (A, B)");
+        assert_eq!(liquidity_pool.trade_reserves(true), (2_000, 1_000), "Token B in should read (B, A)");
+
+        liquidity_pool.set_trade_reserves(true, (2_100, 950));
+        assert_eq!((liquidity_pool.reserve_a, liquidity_pool.reserve_b), (950, 2_100), "Should store token B in as (B, A)");
+        liquidity_pool.set_trade_reserves(false, (1_000, 2_000));
+        assert_eq!((liquidity_pool.reserve_a, liquidity_pool.reserve_b), (1_000, 2_000), "Should store token A in as (A, B)");
+    }
+
+    #[test]
+    fn test_excess() {
+        let liquidity_pool = LiquidityPool {
+            reserve_a: 1_000,
+            reserve_b: 2_000,
+            protocol_fees_a: 10,
+            ..Default::default()
+        };
+        assert_eq!(liquidity_pool.excess(1_010, 2_000).unwrap(), (0, 0), "Should have no excess when the vaults match");
+        assert_eq!(liquidity_pool.excess(1_510, 2_001).unwrap(), (500, 1), "Should return tokens sent straight to the vaults");
+        assert_eq!(liquidity_pool.excess(1_009, 2_000).unwrap_err(), error!(DneError::MathOverflow), "Vault should never hold less than the reserves");
+    }
+
+    #[test]
+    fn test_reserves_exclude_protocol_fees() {
+        let liquidity_pool = LiquidityPool {
+            protocol_fees_a: 300,
+            protocol_fees_b: 0,
+            ..Default::default()
+        };
+        assert_eq!(liquidity_pool.vault_reserves(1_000_300, 500_000).unwrap(), (1_000_000, 500_000), "Should not count protocol fees as reserves");
+        assert_eq!(liquidity_pool.vault_reserves(299, 500_000).unwrap_err(), error!(DneError::MathOverflow), "Vault should never hold less than the protocol fees");
+    }
+
+    #[test]
+    fn test_set_fee_bps() {
+        let mut liquidity_pool = LiquidityPool::default();
+        liquidity_pool.set_fee_bps(5).unwrap();
+        assert_eq!(liquidity_pool.fee_bps, 5, "Should set the fee");
+        let result = liquidity_pool.set_fee_bps(quote::MAX_FEE_BPS + 1);
+        assert_eq!(result.unwrap_err(), error!(DneError::FeeTooHigh), "Should reject a fee above the maximum");
+        assert_eq!(liquidity_pool.fee_bps, 5, "Should keep the old fee");
+    }
+
+    #[test]
+    fn test_set_dynamic_fee_resets_volatility() {
+        let mut liquidity_pool = LiquidityPool {
+            volatility_bps: 500,
+            ..Default::default()
+        };
+        liquidity_pool.set_dynamic_fee(true, 42);
+        assert!(liquidity_pool.dynamic_fee, "Should enable the dynamic fee");
+        assert_eq!(liquidity_pool.volatility_bps, 0, "Should reset the volatility");
+        assert_eq!(liquidity_pool.volatility_slot, 42, "Should start decaying from the current slot");
+    }
+
+    #[test]
+    fn test_current_fee_bps_flat() {
+        let liquidity_pool = LiquidityPool {
+            fee_bps: 30,
+            volatility_bps: 500,
+            ..Default::default()
+        };
+        assert_eq!(liquidity_pool.current_fee_bps(0), 30, "Flat fee pools should ignore volatility");
+    }
+
+    #[test]
+    fn test_current_fee_bps_dynamic() {
+        let liquidity_pool = LiquidityPool {
+            fee_bps: 30,
+            dynamic_fee: true,
+            volatility_bps: 500,
+            volatility_slot: 1000,
+            ..Default::default()
+        };
+        assert_eq!(liquidity_pool.current_fee_bps(1000), 80, "Should add 10% of the volatility to the fee");
+        assert_eq!(liquidity_pool.current_fee_bps(1000 + quote::VOLATILITY_HALF_LIFE_SLOTS), 55, "Should decay the volatility");
+        assert_eq!(liquidity_pool.current_fee_bps(u64::MAX), 30, "Should decay back to the base fee");
+    }
+
+    #[test]
+    fn test_record_trade() {
+        let mut liquidity_pool = LiquidityPool {
+            fee_bps: 30,
+            dynamic_fee: true,
+            ..Default::default()
+        };
+        // Selling token A moves its price from 1 to 0.81
+        liquidity_pool.record_trade(10, false, (1_000_000, 1_000_000), (1_111_111, 900_000)).unwrap();
+        assert_eq!(liquidity_pool.volatility_bps, 1899, "Should record a 19% move");
+        assert_eq!(liquidity_pool.volatility_slot, 10, "Should record the slot");
+
+        // Buying it back moves the price from 0.81 to 1, measured on token A from the token B side too
+        liquidity_pool.record_trade(10, true, (900_000, 1_111_111), (1_000_000, 1_000_000)).unwrap();
+        assert_eq!(liquidity_pool.volatility_bps, 1899 + 2345, "Should add the move back up");
+    }
+
+    #[test]
+    fn test_record_trade_flat_fee() {
+        let mut liquidity_pool = LiquidityPool::default();
+        liquidity_pool.record_trade(10, false, (1_000_000, 1_000_000), (1_111_111, 900_000)).unwrap();
+        assert_eq!(liquidity_pool.volatility_bps, 0, "Flat fee pools should not track volatility");
+    }
+
+    #[test]
+    fn test_accrue_protocol_fee() {
+        let mut liquidity_pool = LiquidityPool::default();
+        liquidity_pool.accrue_protocol_fee(false, 100).unwrap();
+        liquidity_pool.accrue_protocol_fee(true, 50).unwrap();
+        liquidity_pool.accrue_protocol_fee(false, 25).unwrap();
+        assert_eq!(liquidity_pool.protocol_fees_a, 125, "Should accrue token A fees");
+        assert_eq!(liquidity_pool.protocol_fees_b, 50, "Should accrue token B fees");
+    }
+
+    #[test]
+    fn test_set_fee_tiers() {
+        let mut global_config = GlobalConfig::default();
+        global_config.set_fee_tiers(30, vec![5, 30, 100]).unwrap();
+        assert_eq!(global_config.default_fee_bps, 30, "Should set the default fee");
+        assert_eq!(global_config.fee_tiers, vec![5, 30, 100], "Should set the fee tiers");
+
+        let result = global_config.set_fee_tiers(25, vec![5, 30]);
+        assert_eq!(result.unwrap_err(), error!(DneError::FeeTierNotAllowed), "Should reject a default fee outside the tiers");
+        let result = global_config.set_fee_tiers(5, vec![5, quote::MAX_FEE_BPS + 1]);
+        assert_eq!(result.unwrap_err(), error!(DneError::FeeTooHigh), "Should reject a tier above the maximum fee");
+        let result = global_config.set_fee_tiers(1, (1..=GlobalConfig::MAX_FEE_TIERS as u16 + 1).collect());
+        assert_eq!(result.unwrap_err(), error!(DneError::TooManyFeeTiers), "Should reject too many tiers");
+        assert_eq!(global_config.fee_tiers, vec![5, 30, 100], "Should keep the old tiers");
+    }
+
+    #[test]
+    fn test_pool_fee_bps() {
+        let global_config = GlobalConfig {
+            default_fee_bps: 30,
+            fee_tiers: vec![5, 30, 100],
+            ..Default::default()
+        };
+        assert_eq!(global_config.pool_fee_bps(None).unwrap(), 30, "Should fall back to the default fee");
+        assert_eq!(global_config.pool_fee_bps(Some(100)).unwrap(), 100, "Should accept an allowed tier");
+        let result = global_config.pool_fee_bps(Some(50));
+        assert_eq!(result.unwrap_err(), error!(DneError::FeeTierNotAllowed), "Should reject a fee outside the tiers");
+    }
+
+    #[test]
+    fn test_check_fee_tier() {
+        let global_config = GlobalConfig {
+            default_fee_bps: 30,
+            fee_tiers: vec![5, 30, 100],
+            ..Default::default()
+        };
+        assert!(global_config.check_fee_tier(5).is_ok(), "Should accept an allowed tier");
+        let result = global_config.check_fee_tier(quote::MAX_FEE_BPS);
+        assert_eq!(result.unwrap_err(), error!(DneError::FeeTierNotAllowed), "Should reject a fee outside the tiers");
+    }
+
+    #[test]
+    fn test_config_and_registry_size() {
+        let global_config = GlobalConfig {
+            fee_tiers: vec![0; GlobalConfig::MAX_FEE_TIERS],
+            ..Default::default()
+        };
+        assert_eq!(global_config.try_to_vec().unwrap().len() + 8, GlobalConfig::SPACE, "Config space should fit the maximum number of tiers");
+
+        assert_eq!(std::mem::size_of::<PoolRegistry>(), 8, "Registry header should have no padding");
+        assert_eq!(std::mem::size_of::<PoolEntry>(), 3 * 32, "Entries should have no padding");
+        assert_eq!(PoolRegistry::space(2), 8 + 8 + (2 * 3 * 32), "Registry space should fit its entries");
+    }
+
+    #[test]
+    fn test_pool_registry_push() {
+        let mut data = vec![0; PoolRegistry::space(2)];
+        let (registry, entries) = PoolRegistry::split_mut(&mut data).unwrap();
+        let first = PoolEntry { pool: Pubkey::new_unique(), ..Default::default() };
+        let second = PoolEntry { pool: Pubkey::new_unique(), ..Default::default() };
+        registry.push(entries, first).unwrap();
+        registry.push(entries, second).unwrap();
+        assert_eq!(registry.pool_count, 2, "Should count both pools");
+        assert_eq!(entries, [first, second], "Should list the pools in creation order");
+
+        let result = registry.push(entries, PoolEntry::default());
+        assert_eq!(result.unwrap_err(), error!(DneError::InvalidPoolAccount), "Should reject a pool the account has no room for");
+        assert_eq!(registry.pool_count, 2, "Should keep the count");
+    }
+
+    #[test]
+    fn test_pool_registry_short_account() {
+        let mut data = vec![0; PoolRegistry::space(0) - 1];
+        assert!(PoolRegistry::split_mut(&mut data).is_err(), "Should reject an account without a full header");
+    }
+
+    #[test]
+    fn test_update_price_cumulative() {
+        let mut liquidity_pool = LiquidityPool {
+            reserve_a: 1_000,
+            reserve_b: 2_000,
+            ..Default::default()
+        };
+        // The first update only starts the clock
+        liquidity_pool.update_price_cumulative(100);
+        assert_eq!(liquidity_pool.price_a_cumulative, 0, "Should not accumulate on the first update");
+        assert_eq!(liquidity_pool.price_timestamp, 100, "Should start the clock");
+
+        liquidity_pool.update_price_cumulative(110);
+        assert_eq!(liquidity_pool.price_a_cumulative, 2 * quote::PRICE_SCALE * 10, "Token A was worth 2 B for 10 seconds");
+        assert_eq!(liquidity_pool.price_b_cumulative, quote::PRICE_SCALE / 2 * 10, "Token B was worth 0.5 A for 10 seconds");
+
+        // A second update in the same second adds nothing
+        liquidity_pool.update_price_cumulative(110);
+        assert_eq!(liquidity_pool.price_a_cumulative, 2 * quote::PRICE_SCALE * 10, "Should not accumulate without time passing");
+    }
+
+    #[test]
+    fn test_update_price_cumulative_empty_pool() {
+        let mut liquidity_pool = LiquidityPool {
+            price_timestamp: 100,
+            ..Default::default()
+        };
+        liquidity_pool.update_price_cumulative(200);
+        assert_eq!(liquidity_pool.price_a_cumulative, 0, "Empty pool has no price to accumulate");
+        assert_eq!(liquidity_pool.price_timestamp, 200, "Should still move the clock");
     }
-}
\ No newline at end of file
+
+    // A pool with 1_000 token A and 4_000 token B, whose accumulators were last updated at timestamp
+    fn observed_pool(timestamp: i64) -> LiquidityPool {
+        LiquidityPool {
+            reserve_a: 1_000,
+            reserve_b: 4_000,
+            price_timestamp: timestamp,
+            ..Default::default()
+        }
+    }
+
+    // Data of an observations account with room for capacity slots
+    fn observations_data(capacity: usize) -> Vec<u8> {
+        vec![0; Observations::space(capacity)]
+    }
+
+    #[test]
+    fn test_observations_record() {
+        let mut data = observations_data(1);
+        let (observations, slots) = Observations::split_mut(&mut data).unwrap();
+        observations.initialize(Pubkey::new_unique(), 255);
+        let mut liquidity_pool = observed_pool(100);
+
+        observations.record(slots, &liquidity_pool).unwrap();
+        assert_eq!(observations.cardinality, 1, "First observation should start the buffer");
+        assert_eq!(observations.latest(slots).unwrap().unwrap().timestamp, 100, "Should record the timestamp");
+
+        // A second record in the same second is ignored
+        observations.record(slots, &liquidity_pool).unwrap();
+        assert_eq!(observations.index, 0, "Should write once per timestamp");
+
+        liquidity_pool.update_price_cumulative(110);
+        observations.record(slots, &liquidity_pool).unwrap();
+        let latest = observations.latest(slots).unwrap().unwrap();
+        assert_eq!(observations.index, 0, "A single slot should be overwritten");
+        assert_eq!(latest.price_a_cumulative, 4 * quote::PRICE_SCALE * 10, "Token A was worth 4 B for 10 seconds");
+        assert_eq!(latest.liquidity_cumulative, 2_000 * 10, "Liquidity was 2_000 for 10 seconds");
+    }
+
+    #[test]
+    fn test_observations_grow() {
+        let mut data = observations_data(1);
+        let mut liquidity_pool = observed_pool(100);
+        {
+            let (observations, slots) = Observations::split_mut(&mut data).unwrap();
+            observations.initialize(Pubkey::new_unique(), 255);
+            observations.record(slots, &liquidity_pool).unwrap();
+        }
+
+        // increase_observation_cardinality reallocates the account, the new slots are not in use yet
+        data.resize(Observations::space(3), 0);
+        let (observations, slots) = Observations::split_mut(&mut data).unwrap();
+        assert_eq!(Observations::capacity(slots), 3, "Should add slots");
+        assert_eq!(observations.cardinality, 1, "New slots are not in use yet");
+
+        // Four records fill the three slots and wrap around to the first one
+        for timestamp in [110, 120, 130] {
+            liquidity_pool.update_price_cumulative(timestamp);
+            observations.record(slots, &liquidity_pool).unwrap();
+        }
+        assert_eq!(observations.cardinality, 3, "All slots should be in use");
+        assert_eq!(observations.index, 0, "Should wrap around");
+        assert_eq!(observations.nth_oldest(slots, 0).unwrap().timestamp, 110, "Oldest should be the one after the newest");
+        assert_eq!(observations.latest(slots).unwrap().unwrap().timestamp, 130, "Newest should be in the first slot");
+    }
+
+    #[test]
+    fn test_observations_observe() {
+        let mut data = observations_data(4);
+        let (observations, slots) = Observations::split_mut(&mut data).unwrap();
+        observations.initialize(Pubkey::new_unique(), 255);
+        let mut liquidity_pool = observed_pool(100);
+        observations.record(slots, &liquidity_pool).unwrap();
+        liquidity_pool.update_price_cumulative(110);
+        observations.record(slots, &liquidity_pool).unwrap();
+
+        // The price doubles at 110
+        liquidity_pool.reserve_b = 8_000;
+        liquidity_pool.update_price_cumulative(130);
+        observations.record(slots, &liquidity_pool).unwrap();
+
+        let (observations, slots) = Observations::split(&data).unwrap();
+        let scale = quote::PRICE_SCALE;
+        let at = |seconds_ago| observations.observe(slots, &liquidity_pool, 140, seconds_ago).unwrap();
+        assert_eq!(at(40).price_a_cumulative, 0, "Should return the oldest observation");
+        assert_eq!(at(35).price_a_cumulative, 4 * scale * 5, "Should interpolate between observations");
+        assert_eq!(at(20).price_a_cumulative, 4 * scale * 10 + 8 * scale * 10, "Should interpolate after the price change");
+        assert_eq!(at(0).price_a_cumulative, 4 * scale * 10 + 8 * scale * 30, "Should carry the latest observation on to now");
+
+        // The time-weighted price over the last 30 seconds
+        let twap = (at(0).price_a_cumulative - at(30).price_a_cumulative) / 30;
+        assert_eq!(twap, 8 * scale, "Token A was worth 8 B for the last 30 seconds");
+
+        let result = observations.observe(slots, &liquidity_pool, 140, 41);
+        assert_eq!(result.unwrap_err(), error!(DneError::ObservationTooOld), "Should not observe before the oldest observation");
+    }
+
+    #[test]
+    fn test_observations_observe_empty() {
+        let mut data = observations_data(1);
+        Observations::split_mut(&mut data).unwrap().0.initialize(Pubkey::new_unique(), 255);
+        let (observations, slots) = Observations::split(&data).unwrap();
+        let result = observations.observe(slots, &observed_pool(100), 100, 0);
+        assert_eq!(result.unwrap_err(), error!(DneError::ObservationTooOld), "Should have nothing to observe");
+    }
+
+    #[test]
+    fn test_observations_malformed_account() {
+        let mut data = vec![0; Observations::space(0) - 1];
+        assert_eq!(Observations::split(&data).err(), Some(error!(DneError::InvalidPoolAccount)), "Should reject an account without a full header");
+        assert_eq!(Observations::split_mut(&mut data).err(), Some(error!(DneError::InvalidPoolAccount)), "Should reject an account without a full header");
+
+        // A header that claims more slots than the account holds
+        let mut data = observations_data(2);
+        let (observations, slots) = Observations::split_mut(&mut data).unwrap();
+        observations.cardinality = 5;
+        observations.index = 4;
+        let result = observations.record(slots, &observed_pool(100));
+        assert_eq!(result.unwrap_err(), error!(DneError::InvalidPoolAccount), "Should not read past the slots");
+        let result = observations.observe(slots, &observed_pool(100), 100, 0);
+        assert_eq!(result.unwrap_err(), error!(DneError::InvalidPoolAccount), "Should not read past the slots");
+    }
+
+    #[test]
+    fn test_observations_size() {
+        assert_eq!(std::mem::size_of::<Observations>(), 32 + 2 + 2 + 1 + 3, "Header should have no padding");
+        let mut observation = Observation { timestamp: 1, ..Default::default() };
+        observation.liquidity_cumulative = u128::MAX;
+        assert_eq!(observation.try_to_vec().unwrap().len(), Observation::SIZE, "A slot should fit an observation");
+    }
+
+    #[test]
+    fn test_observations_max_cardinality() {
+        // Only the header and one slot at a time are ever read, so a full buffer works like a small one
+        let max = Observations::MAX_CARDINALITY;
+        let mut data = observations_data(max as usize);
+        let (observations, slots) = Observations::split_mut(&mut data).unwrap();
+        observations.initialize(Pubkey::new_unique(), 255);
+        assert_eq!(Observations::capacity(slots), max as usize, "Should have room for every slot");
+
+        // Write every slot and wrap around past the end
+        let mut liquidity_pool = observed_pool(1);
+        for timestamp in 1..=(max as i64 + 10) {
+            liquidity_pool.update_price_cumulative(timestamp);
+            observations.record(slots, &liquidity_pool).unwrap();
+        }
+        assert_eq!(observations.cardinality, max, "All slots should be in use");
+        assert_eq!(observations.index, 9, "Should wrap around");
+
+        let (observations, slots) = Observations::split(&data).unwrap();
+        let now = max as i64 + 10;
+        assert_eq!(observations.nth_oldest(slots, 0).unwrap().timestamp, 11, "Oldest should be the one after the newest");
+        let oldest = observations.observe(slots, &liquidity_pool, now, max as u32 - 1).unwrap();
+        assert_eq!(oldest.timestamp, 11, "Should observe back to the oldest observation");
+        let latest = observations.observe(slots, &liquidity_pool, now, 0).unwrap();
+        assert_eq!(latest.price_a_cumulative - oldest.price_a_cumulative, 4 * quote::PRICE_SCALE * (max as u128 - 1), "Token A was worth 4 B the whole time");
+    }
+}
@@ -0,0 +1,527 @@
+// Pool math shared by the on-chain instructions and off-chain quoting.
+// Nothing in this module logs or touches accounts, so a backend can depend on the crate with the
+// no-entrypoint feature and get exactly the numbers the program will execute with.
+// All math is done on u128 so the intermediate products of two u64 values can never overflow.
+// Every division rounds in favor of the pool.
+
+// Kept in its own module so the macro does not pick up the anchor Result alias
+#[allow(clippy::all)]
+mod big_int {
+    uint::construct_uint! {
+        // 256 bit integer for intermediate products that do not fit in a u128
+        pub struct U256(4);
+    }
+}
+use big_int::U256;
+
+// LP tokens locked forever on the first deposit
+pub const MINIMUM_LIQUIDITY: u64 = 1000;
+
+// Swap fees and price impact are expressed in basis points so they can be computed with integer math
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+// 0.3% fee for pools that do not pick their own
+pub const DEFAULT_FEE_BPS: u16 = 30;
+
+// No pool can charge more than a 10% fee
+pub const MAX_FEE_BPS: u16 = 1_000;
+
+// The protocol can take at most half of the swap fee, the rest always goes to LPs
+pub const MAX_PROTOCOL_FEE_BPS: u16 = 5_000;
+
+// Integrators can take at most 1% of a swap input as a referral fee
+pub const MAX_REFERRAL_FEE_BPS: u16 = 100;
+
+// Volatility accumulated by a dynamic fee pool halves for every VOLATILITY_HALF_LIFE_SLOTS slots, about a minute
+pub const VOLATILITY_HALF_LIFE_SLOTS: u64 = 150;
+
+// Share of the accumulated volatility that is added to the base fee of a dynamic fee pool, in basis points
+pub const VOLATILITY_FEE_SHARE_BPS: u64 = 1_000;
+
+// Volatility stops accumulating once recent moves add up to 100%
+pub const MAX_VOLATILITY_BPS: u32 = 10_000;
+
+// Prices are returned as fixed point numbers with 18 decimals
+pub const PRICE_SCALE: u128 = 1_000_000_000_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuoteError {
+    InsufficientLiquidity,
+    MathOverflow,
+    SlippageExceeded,
+    InitialDepositTooSmall,
+    FeeTooHigh,
+}
+
+pub type QuoteResult<T> = core::result::Result<T, QuoteError>;
+
+// Constant product swap: amount_out = reserve_out * amount_in_after_fee / (reserve_in + amount_in_after_fee).
+// The fee is taken from the input and stays in the pool, and the output is rounded down.
+pub fn swap_output(token_balance_in: u64, token_balance_out: u64, amount_in: u64, fee_bps: u16) -> QuoteResult<u64> {
+    if token_balance_in == 0 || token_balance_out == 0 {
+        return Err(QuoteError::InsufficientLiquidity);
+    }
+    let fee_remainder = fee_remainder(fee_bps)?;
+
+    let amount_in_with_fee = (amount_in as u128)
+        .checked_mul(fee_remainder)
+        .ok_or(QuoteError::MathOverflow)?;
+    let numerator = amount_in_with_fee
+        .checked_mul(token_balance_out as u128)
+        .ok_or(QuoteError::MathOverflow)?;
+    let denominator = (token_balance_in as u128)
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .and_then(|balance| balance.checked_add(amount_in_with_fee))
+        .ok_or(QuoteError::MathOverflow)?;
+
+    // The output is always below token_balance_out, so it fits in a u64
+    u64::try_from(numerator / denominator).map_err(|_| QuoteError::MathOverflow)
+}
+
+// Inverse of swap_output: the input needed to receive exactly amount_out, including the fee.
+// amount_in = reserve_in * amount_out / ((reserve_out - amount_out) * (1 - fee)), rounded up so the pool never loses.
+pub fn swap_input(token_balance_in: u64, token_balance_out: u64, amount_out: u64, fee_bps: u16) -> QuoteResult<u64> {
+    if token_balance_in == 0 || token_balance_out == 0 || amount_out >= token_balance_out {
+        return Err(QuoteError::InsufficientLiquidity);
+    }
+    let fee_remainder = fee_remainder(fee_bps)?;
+
+    let numerator = (token_balance_in as u128)
+        .checked_mul(amount_out as u128)
+        .and_then(|product| product.checked_mul(BPS_DENOMINATOR as u128))
+        .ok_or(QuoteError::MathOverflow)?;
+    let denominator = ((token_balance_out - amount_out) as u128)
+        .checked_mul(fee_remainder)
+        .ok_or(QuoteError::MathOverflow)?;
+
+    u64::try_from(numerator.div_ceil(denominator)).map_err(|_| QuoteError::MathOverflow)
+}
+
+// The whole fee paid on a swap of amount_in, rounded down. The LPs and the protocol share it.
+pub fn swap_fee(amount_in: u64, fee_bps: u16) -> QuoteResult<u64> {
+    if fee_bps > MAX_FEE_BPS {
+        return Err(QuoteError::FeeTooHigh);
+    }
+    // The fee is below one, so the result is below amount_in
+    Ok(((amount_in as u128) * (fee_bps as u128) / (BPS_DENOMINATOR as u128)) as u64)
+}
+
+// The protocol's cut of the fee on a swap of amount_in, rounded down so the dust stays with LPs.
+// protocol_fee_bps is a share of the swap fee, not of the swap.
+pub fn protocol_fee(amount_in: u64, fee_bps: u16, protocol_fee_bps: u16) -> QuoteResult<u64> {
+    if fee_bps > MAX_FEE_BPS || protocol_fee_bps > MAX_PROTOCOL_FEE_BPS {
+        return Err(QuoteError::FeeTooHigh);
+    }
+    let bps_squared = (BPS_DENOMINATOR as u128) * (BPS_DENOMINATOR as u128);
+    // Both shares are below one, so the product fits in a u128 and the result is below amount_in
+    let fee = (amount_in as u128) * (fee_bps as u128) * (protocol_fee_bps as u128) / bps_squared;
+    Ok(fee as u64)
+}
+
+// The referrer's cut of a swap input, rounded down. It is taken before the swap, so only the rest pays the swap fee.
+pub fn referral_fee(amount_in: u64, referral_fee_bps: u16) -> QuoteResult<u64> {
+    if referral_fee_bps > MAX_REFERRAL_FEE_BPS {
+        return Err(QuoteError::FeeTooHigh);
+    }
+    // The share is below one, so the result is below amount_in
+    Ok(((amount_in as u128) * (referral_fee_bps as u128) / (BPS_DENOMINATOR as u128)) as u64)
+}
+
+// Marginal price of the input token in units of the output token, scaled by PRICE_SCALE and rounded down.
+pub fn spot_price(token_balance_in: u64, token_balance_out: u64) -> QuoteResult<u128> {
+    if token_balance_in == 0 || token_balance_out == 0 {
+        return Err(QuoteError::InsufficientLiquidity);
+    }
+    // reserve_out fits in 64 bits and PRICE_SCALE in 60, so the product fits in a u128
+    Ok(token_balance_out as u128 * PRICE_SCALE / token_balance_in as u128)
+}
+
+// Adds price held for elapsed seconds to a cumulative price, like the Uniswap v2 price accumulators.
+// The sum wraps on overflow. A time-weighted price is the wrapping difference of two readings divided by the time between them.
+pub fn accumulate_price(price_cumulative: u128, price: u128, elapsed: u64) -> u128 {
+    price_cumulative.wrapping_add(price.wrapping_mul(elapsed as u128))
+}
+
+// How much worse than the spot price a swap of amount_in executes, in basis points, fee included.
+// The execution price is rounded down, so the impact is rounded up.
+pub fn price_impact(token_balance_in: u64, token_balance_out: u64, amount_in: u64, fee_bps: u16) -> QuoteResult<u64> {
+    let amount_out = swap_output(token_balance_in, token_balance_out, amount_in, fee_bps)?;
+    if amount_in == 0 {
+        return Ok(0);
+    }
+
+    // execution / spot = (amount_out / amount_in) / (reserve_out / reserve_in)
+    let numerator = U256::from(amount_out) * U256::from(token_balance_in) * U256::from(BPS_DENOMINATOR);
+    let denominator = U256::from(amount_in) * U256::from(token_balance_out);
+    // The execution price is never above the spot price, so this is at most BPS_DENOMINATOR
+    let execution_bps = (numerator / denominator).as_u64();
+
+    Ok(BPS_DENOMINATOR - execution_bps)
+}
+
+// How far the price moved between two spot prices, in basis points of the first one.
+// Moves of 100% or more are capped at MAX_VOLATILITY_BPS.
+pub fn price_move_bps(price_before: u128, price_after: u128) -> u32 {
+    if price_before == 0 {
+        return MAX_VOLATILITY_BPS;
+    }
+    let difference = price_before.abs_diff(price_after);
+    // Prices can be close to 128 bits, so the product is done in 256 bits
+    let move_bps = U256::from(difference) * U256::from(BPS_DENOMINATOR) / U256::from(price_before);
+    move_bps.min(U256::from(MAX_VOLATILITY_BPS)).as_u32()
+}
+
+// Volatility left after slots_elapsed slots without trades, halving every VOLATILITY_HALF_LIFE_SLOTS.
+pub fn decay_volatility(volatility_bps: u32, slots_elapsed: u64) -> u32 {
+    let half_lives = slots_elapsed / VOLATILITY_HALF_LIFE_SLOTS;
+    if half_lives >= u32::BITS as u64 {
+        return 0;
+    }
+    volatility_bps >> half_lives
+}
+
+// Adds a price move to the volatility, up to MAX_VOLATILITY_BPS.
+pub fn accumulate_volatility(volatility_bps: u32, move_bps: u32) -> u32 {
+    volatility_bps.saturating_add(move_bps).min(MAX_VOLATILITY_BPS)
+}
+
+// The fee of a dynamic fee pool: the base fee plus VOLATILITY_FEE_SHARE_BPS of the volatility, at most MAX_FEE_BPS.
+pub fn dynamic_fee(base_fee_bps: u16, volatility_bps: u32) -> u16 {
+    let volatility_fee = volatility_bps as u64 * VOLATILITY_FEE_SHARE_BPS / BPS_DENOMINATOR;
+    (base_fee_bps as u64 + volatility_fee).min(MAX_FEE_BPS as u64) as u16
+}
+
+// The amount of the other token worth amount at the current reserve ratio, rounded down.
+pub fn amount_at_ratio(amount: u64, token_balance_in: u64, token_balance_out: u64) -> QuoteResult<u64> {
+    let amount_out = (amount as u128)
+        .checked_mul(token_balance_out as u128)
+        .and_then(|product| product.checked_div(token_balance_in as u128))
+        .ok_or(QuoteError::MathOverflow)?;
+    u64::try_from(amount_out).map_err(|_| QuoteError::MathOverflow)
+}
+
+// Picks the deposit amounts that match the current reserve ratio, like the Uniswap v2 router.
+// One side is always the desired amount, and the other is the amount needed to keep the ratio, rounded down.
+// An empty pool takes both desired amounts as they are, since they set the initial price.
+pub fn optimal_deposit(
+    token_a_balance: u64,
+    token_b_balance: u64,
+    amount_a_desired: u64,
+    amount_b_desired: u64,
+    amount_a_min: u64,
+    amount_b_min: u64,
+) -> QuoteResult<(u64, u64)> {
+    if token_a_balance == 0 && token_b_balance == 0 {
+        return Ok((amount_a_desired, amount_b_desired));
+    }
+    if token_a_balance == 0 || token_b_balance == 0 {
+        return Err(QuoteError::InsufficientLiquidity);
+    }
+
+    let amount_b_optimal = amount_at_ratio(amount_a_desired, token_a_balance, token_b_balance)?;
+    if amount_b_optimal <= amount_b_desired {
+        if amount_b_optimal < amount_b_min {
+            return Err(QuoteError::SlippageExceeded);
+        }
+        Ok((amount_a_desired, amount_b_optimal))
+    } else {
+        let amount_a_optimal = amount_at_ratio(amount_b_desired, token_b_balance, token_a_balance)?;
+        if amount_a_optimal < amount_a_min {
+            return Err(QuoteError::SlippageExceeded);
+        }
+        Ok((amount_a_optimal, amount_b_desired))
+    }
+}
+
+// LP tokens minted to the depositor for amount_a and amount_b.
+// The first deposit into an empty pool mints sqrt(amount_a * amount_b) minus the locked MINIMUM_LIQUIDITY.
+// Later deposits mint lp_supply * min(amount_a / reserve_a, amount_b / reserve_b), each side rounded down,
+// so the depositor never gets more than their share.
+pub fn lp_tokens_for_deposit(
+    token_a_balance: u64,
+    token_b_balance: u64,
+    lp_token_supply: u64,
+    amount_a: u64,
+    amount_b: u64,
+) -> QuoteResult<u64> {
+    if token_a_balance == 0 && token_b_balance == 0 {
+        // The product of two u64 values always fits in a u128.
+        let product = (amount_a as u128) * (amount_b as u128);
+        let liquidity = u64::try_from(integer_sqrt(product)).map_err(|_| QuoteError::MathOverflow)?;
+        if liquidity <= MINIMUM_LIQUIDITY {
+            return Err(QuoteError::InitialDepositTooSmall);
+        }
+        return Ok(liquidity - MINIMUM_LIQUIDITY);
+    }
+
+    let lp_supply = lp_token_supply as u128;
+    let lp_for_a = (amount_a as u128)
+        .checked_mul(lp_supply)
+        .and_then(|product| product.checked_div(token_a_balance as u128))
+        .ok_or(QuoteError::MathOverflow)?;
+    let lp_for_b = (amount_b as u128)
+        .checked_mul(lp_supply)
+        .and_then(|product| product.checked_div(token_b_balance as u128))
+        .ok_or(QuoteError::MathOverflow)?;
+    u64::try_from(lp_for_a.min(lp_for_b)).map_err(|_| QuoteError::MathOverflow)
+}
+
+// Token A and B returned for burning lp_token_amount. Each side is (lp_amount * reserve) / lp_supply, rounded down.
+pub fn tokens_for_burn(
+    lp_token_amount: u64,
+    lp_token_supply: u64,
+    token_a_balance: u64,
+    token_b_balance: u64,
+) -> QuoteResult<(u64, u64)> {
+    let amount_a = (lp_token_amount as u128)
+        .checked_mul(token_a_balance as u128)
+        .and_then(|product| product.checked_div(lp_token_supply as u128))
+        .ok_or(QuoteError::MathOverflow)?;
+    let amount_b = (lp_token_amount as u128)
+        .checked_mul(token_b_balance as u128)
+        .and_then(|product| product.checked_div(lp_token_supply as u128))
+        .ok_or(QuoteError::MathOverflow)?;
+    Ok((
+        u64::try_from(amount_a).map_err(|_| QuoteError::MathOverflow)?,
+        u64::try_from(amount_b).map_err(|_| QuoteError::MathOverflow)?,
+    ))
+}
+
+// The amount of a single token paid out for burning lp_token_amount.
+// The share of the other token is swapped into the pool after the share of both tokens has left it.
+pub fn single_token_for_burn(
+    lp_token_amount: u64,
+    lp_token_supply: u64,
+    token_balance_out: u64,
+    token_balance_other: u64,
+    fee_bps: u16,
+) -> QuoteResult<u64> {
+    let (amount_out, amount_other) = tokens_for_burn(lp_token_amount, lp_token_supply, token_balance_out, token_balance_other)?;
+
+    // Burning the whole supply leaves nothing to swap against, swap_output rejects the empty reserves
+    let swap_out = swap_output(
+        token_balance_other - amount_other,
+        token_balance_out - amount_out,
+        amount_other,
+        fee_bps,
+    )?;
+
+    amount_out.checked_add(swap_out).ok_or(QuoteError::MathOverflow)
+}
+
+// The part of a single sided deposit that has to be swapped so the rest matches the post swap reserve ratio.
+// Solves (amount - s) / (reserve_in + s) = out(s) / (reserve_out - out(s)) for s, with the fee taken on the swap:
+// s = (sqrt(reserve_in^2 * (D + g)^2 + 4 * g * D * reserve_in * amount) - reserve_in * (D + g)) / (2 * g)
+// where D is the basis point denominator and g = D - fee. The result is rounded down.
+pub fn single_sided_swap_amount(token_balance_in: u64, amount: u64, fee_bps: u16) -> QuoteResult<u64> {
+    let fee_denominator = U256::from(BPS_DENOMINATOR);
+    let fee_remainder = U256::from(fee_remainder(fee_bps)?);
+    let reserve = U256::from(token_balance_in);
+    let sum = fee_denominator + fee_remainder;
+
+    // reserve_in fits in 64 bits and every constant in 15, so none of these products can overflow 256 bits.
+    let discriminant = reserve * reserve * sum * sum
+        + U256::from(4u8) * fee_remainder * fee_denominator * reserve * U256::from(amount);
+    let swap_amount = (discriminant.integer_sqrt() - reserve * sum) / (U256::from(2u8) * fee_remainder);
+
+    u64::try_from(swap_amount).map_err(|_| QuoteError::MathOverflow)
+}
+
+// Returns the amount of a single sided deposit that is used, the LP tokens minted for it,
+// and the protocol fee taken on the internal swap.
+// The swapped part stays in the pool as if it had been swapped and deposited back.
+// Rounding can leave the matched side a unit short, so only the amount actually used is taken from the user.
+pub fn single_sided_deposit(
+    token_balance_in: u64,
+    token_balance_out: u64,
+    lp_token_supply: u64,
+    amount: u64,
+    fee_bps: u16,
+    protocol_fee_bps: u16,
+) -> QuoteResult<(u64, u64, u64)> {
+    if token_balance_in == 0 || token_balance_out == 0 {
+        return Err(QuoteError::InsufficientLiquidity);
+    }
+
+    let swap_amount = single_sided_swap_amount(token_balance_in, amount, fee_bps)?;
+    let swap_out = swap_output(token_balance_in, token_balance_out, swap_amount, fee_bps)?;
+
+    // Reserves after the internal swap, without the protocol's cut of its fee
+    let swap_protocol_fee = protocol_fee(swap_amount, fee_bps, protocol_fee_bps)?;
+    let new_balance_in = token_balance_in.checked_add(swap_amount - swap_protocol_fee).ok_or(QuoteError::MathOverflow)?;
+    let new_balance_out = token_balance_out - swap_out;
+
+    // The optimal deposit keeps the ratio, so at most the rest of the deposit and the swap output are used
+    let (deposit_in, deposit_out) = optimal_deposit(new_balance_in, new_balance_out, amount - swap_amount, swap_out, 0, 0)?;
+    let amount_to_mint = lp_tokens_for_deposit(new_balance_in, new_balance_out, lp_token_supply, deposit_in, deposit_out)?;
+
+    Ok((swap_amount + deposit_in, amount_to_mint, swap_protocol_fee))
+}
+
+// The part of the input left after the fee, in basis points
+fn fee_remainder(fee_bps: u16) -> QuoteResult<u128> {
+    if fee_bps > MAX_FEE_BPS {
+        return Err(QuoteError::FeeTooHigh);
+    }
+    Ok((BPS_DENOMINATOR - fee_bps as u64) as u128)
+}
+
+// Floor of the square root, computed with Newton's method so it is exact for every u128.
+pub fn integer_sqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+    let mut x = value;
+    let mut y = (x + value / x) / 2;
+    while y < x {
+        x = y;
+        y = (x + value / x) / 2;
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spot_price() {
+        let price = spot_price(1_000_000_000, 500_000_000).unwrap();
+        assert_eq!(price, PRICE_SCALE / 2, "Token A should be worth half a token B");
+    }
+
+    #[test]
+    fn test_spot_price_empty_pool() {
+        assert_eq!(spot_price(0, 500_000_000), Err(QuoteError::InsufficientLiquidity), "Empty pool has no price");
+    }
+
+    #[test]
+    fn test_accumulate_price() {
+        let cumulative = accumulate_price(0, PRICE_SCALE / 2, 10);
+        let cumulative = accumulate_price(cumulative, PRICE_SCALE, 30);
+        // Half for 10 seconds and one for 30 seconds averages to 0.875
+        assert_eq!(cumulative / 40, PRICE_SCALE * 7 / 8, "Should weight each price by how long it held");
+    }
+
+    #[test]
+    fn test_accumulate_price_wraps() {
+        let start = u128::MAX - PRICE_SCALE;
+        let end = accumulate_price(start, PRICE_SCALE, 5);
+        assert!(end < start, "Should wrap around");
+        assert_eq!(end.wrapping_sub(start) / 5, PRICE_SCALE, "Wrapping difference should still give the average");
+    }
+
+    #[test]
+    fn test_price_impact() {
+        // 100 into 1000 / 1000 returns 90, so the execution price is 0.9 of the spot price
+        let impact = price_impact(1000, 1000, 100, DEFAULT_FEE_BPS).unwrap();
+        assert_eq!(impact, 1000, "Should be 10% price impact");
+    }
+
+    #[test]
+    fn test_price_impact_small_swap_is_the_fee() {
+        // A swap that barely moves the price only pays the 0.3% fee, rounded up
+        let impact = price_impact(1_000_000_000_000, 1_000_000_000_000, 1_000_000, DEFAULT_FEE_BPS).unwrap();
+        assert_eq!(impact, 31, "Should be the fee plus rounding");
+    }
+
+    #[test]
+    fn test_price_impact_zero_amount() {
+        assert_eq!(price_impact(1000, 1000, 0, DEFAULT_FEE_BPS), Ok(0), "Nothing swapped has no price impact");
+    }
+
+    #[test]
+    fn test_swap_input_matches_swap_output() {
+        let amount_in = swap_input(1_000_000_000, 500_000_000, 10_000_000, DEFAULT_FEE_BPS).unwrap();
+        assert!(swap_output(1_000_000_000, 500_000_000, amount_in, DEFAULT_FEE_BPS).unwrap() >= 10_000_000, "Quoted input should buy the output");
+        assert!(swap_output(1_000_000_000, 500_000_000, amount_in - 1, DEFAULT_FEE_BPS).unwrap() < 10_000_000, "Quoted input should be the smallest");
+    }
+
+    #[test]
+    fn test_swap_output_fee() {
+        // 100 into 1000 / 1000 with no fee is 1000 * 100 / 1100 = 90.9
+        assert_eq!(swap_output(1000, 1000, 100, 0), Ok(90), "Should swap without a fee");
+        // With a 1% fee only 99 is swapped, 1000 * 99 / 1099 = 90.08
+        assert_eq!(swap_output(1000, 1000, 100, 100), Ok(90), "Should swap with a 1% fee");
+        // With the maximum 10% fee only 90 is swapped, 1000 * 90 / 1090 = 82.5
+        assert_eq!(swap_output(1000, 1000, 100, MAX_FEE_BPS), Ok(82), "Should swap with a 10% fee");
+    }
+
+    #[test]
+    fn test_protocol_fee() {
+        // A sixth of the 0.3% fee on 1_000_000 is 500
+        assert_eq!(protocol_fee(1_000_000, DEFAULT_FEE_BPS, 1_667), Ok(500), "Should take a sixth of the fee");
+        assert_eq!(protocol_fee(1_000_000, DEFAULT_FEE_BPS, 0), Ok(0), "Should take nothing without a protocol share");
+        assert_eq!(protocol_fee(u64::MAX, MAX_FEE_BPS, MAX_PROTOCOL_FEE_BPS), Ok(u64::MAX / 20), "Should take at most 5% of the input");
+    }
+
+    #[test]
+    fn test_protocol_fee_too_high() {
+        assert_eq!(protocol_fee(1_000_000, DEFAULT_FEE_BPS, MAX_PROTOCOL_FEE_BPS + 1), Err(QuoteError::FeeTooHigh), "Should reject a share above the maximum");
+    }
+
+    #[test]
+    fn test_swap_fee() {
+        assert_eq!(swap_fee(1_000_000, DEFAULT_FEE_BPS), Ok(3_000), "Should take 0.3% of the input");
+        assert_eq!(swap_fee(333, DEFAULT_FEE_BPS), Ok(0), "Should round down");
+        let fee = swap_fee(1_000_000, DEFAULT_FEE_BPS).unwrap();
+        assert!(protocol_fee(1_000_000, DEFAULT_FEE_BPS, MAX_PROTOCOL_FEE_BPS).unwrap() <= fee, "The protocol fee should be part of the swap fee");
+        assert_eq!(swap_fee(1_000_000, MAX_FEE_BPS + 1), Err(QuoteError::FeeTooHigh), "Should reject a fee above the maximum");
+    }
+
+    #[test]
+    fn test_single_sided_deposit_protocol_fee() {
+        let (amount_used, amount_to_mint, _) = single_sided_deposit(1_000_000_000, 500_000_000, 707_106_781, 10_000_000, DEFAULT_FEE_BPS, 0).unwrap();
+        let (amount_used_with_fee, amount_to_mint_with_fee, swap_protocol_fee) =
+            single_sided_deposit(1_000_000_000, 500_000_000, 707_106_781, 10_000_000, DEFAULT_FEE_BPS, MAX_PROTOCOL_FEE_BPS).unwrap();
+        assert_eq!(swap_protocol_fee, protocol_fee(single_sided_swap_amount(1_000_000_000, 10_000_000, DEFAULT_FEE_BPS).unwrap(), DEFAULT_FEE_BPS, MAX_PROTOCOL_FEE_BPS).unwrap());
+        // The protocol fee is not part of the reserves, so less of the deposit is needed to match the ratio
+        assert!(amount_used_with_fee < amount_used, "Should use less of the deposit");
+        assert!(amount_to_mint_with_fee <= amount_to_mint, "Should not mint more LP tokens");
+    }
+
+    #[test]
+    fn test_referral_fee() {
+        assert_eq!(referral_fee(1_000_000, 25), Ok(2_500), "Should take 0.25% of the input");
+        assert_eq!(referral_fee(399, 25), Ok(0), "Should round down");
+        assert_eq!(referral_fee(u64::MAX, MAX_REFERRAL_FEE_BPS), Ok(u64::MAX / 100), "Should take at most 1% of the input");
+        assert_eq!(referral_fee(1_000_000, MAX_REFERRAL_FEE_BPS + 1), Err(QuoteError::FeeTooHigh), "Should reject a fee above the maximum");
+    }
+
+    #[test]
+    fn test_price_move_bps() {
+        assert_eq!(price_move_bps(PRICE_SCALE, PRICE_SCALE), 0, "Same price should not move");
+        assert_eq!(price_move_bps(PRICE_SCALE, PRICE_SCALE * 101 / 100), 100, "Should be a 1% move up");
+        assert_eq!(price_move_bps(PRICE_SCALE, PRICE_SCALE * 99 / 100), 100, "Should be a 1% move down");
+        assert_eq!(price_move_bps(PRICE_SCALE, PRICE_SCALE * 3), MAX_VOLATILITY_BPS, "Should cap large moves");
+        assert_eq!(price_move_bps(u128::MAX / 2, u128::MAX), MAX_VOLATILITY_BPS, "Should not overflow on large prices");
+    }
+
+    #[test]
+    fn test_decay_volatility() {
+        assert_eq!(decay_volatility(1000, 0), 1000, "Should not decay in the same slot");
+        assert_eq!(decay_volatility(1000, VOLATILITY_HALF_LIFE_SLOTS - 1), 1000, "Should not decay before a half life");
+        assert_eq!(decay_volatility(1000, VOLATILITY_HALF_LIFE_SLOTS), 500, "Should halve after a half life");
+        assert_eq!(decay_volatility(1000, 3 * VOLATILITY_HALF_LIFE_SLOTS), 125, "Should halve every half life");
+        assert_eq!(decay_volatility(1000, u64::MAX), 0, "Should decay to nothing");
+    }
+
+    #[test]
+    fn test_accumulate_volatility() {
+        assert_eq!(accumulate_volatility(100, 250), 350, "Should add the move");
+        assert_eq!(accumulate_volatility(MAX_VOLATILITY_BPS, 250), MAX_VOLATILITY_BPS, "Should cap the volatility");
+    }
+
+    #[test]
+    fn test_dynamic_fee() {
+        assert_eq!(dynamic_fee(DEFAULT_FEE_BPS, 0), DEFAULT_FEE_BPS, "Should be the base fee without volatility");
+        // 10% of a 5% move adds 50 bps
+        assert_eq!(dynamic_fee(DEFAULT_FEE_BPS, 500), 80, "Should add a share of the volatility");
+        assert_eq!(dynamic_fee(500, MAX_VOLATILITY_BPS), MAX_FEE_BPS, "Should cap the fee");
+    }
+
+    #[test]
+    fn test_swap_output_fee_too_high() {
+        assert_eq!(swap_output(1000, 1000, 100, MAX_FEE_BPS + 1), Err(QuoteError::FeeTooHigh), "Should reject a fee above the maximum");
+    }
+}
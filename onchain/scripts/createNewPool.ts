@@ -2,7 +2,7 @@ import * as anchor from "@coral-xyz/anchor";
 import {Program} from "@coral-xyz/anchor";
 import {
     ASSOCIATED_TOKEN_PROGRAM_ID,
-    createAssociatedTokenAccount, createMint,
+    createAssociatedTokenAccount,
     getAssociatedTokenAddress,
     mintTo,
     TOKEN_2022_PROGRAM_ID
@@ -141,11 +141,27 @@ function derivePDAAddresses(tokenA: PublicKey, tokenB: PublicKey, program: Progr
     );
 
     const [lpTokenAPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("pool_token_a"), tokenA.toBuffer()],
+        [Buffer.from("pool_token_a"), liquidityPoolPda.toBuffer()],
         program.programId
     );
     const [lpTokenBPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("pool_token_b"), tokenB.toBuffer()],
+        [Buffer.from("pool_token_b"), liquidityPoolPda.toBuffer()],
+        program.programId
+    );
+
+    const [lockedLpTokenPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("locked_liquidity"), liquidityPoolPda.toBuffer()],
+        program.programId
+    );
+
+    const [observationsPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("observations"), liquidityPoolPda.toBuffer()],
+        program.programId
+    );
+
+    // The LP token mint is created by create_pool
+    const [lpTokenPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("lp_token"), liquidityPoolPda.toBuffer()],
         program.programId
     );
 
@@ -154,6 +170,9 @@ function derivePDAAddresses(tokenA: PublicKey, tokenB: PublicKey, program: Progr
         bump,
         lpTokenAPda,
         lpTokenBPda,
+        lockedLpTokenPda,
+        observationsPda,
+        lpTokenPda,
     }
 }
 
@@ -186,26 +205,36 @@ const initializePool = async (provider: anchor.AnchorProvider, tokenA: anchor.we
         liquidityPoolPda,
         bump,
         lpTokenAPda,
-        lpTokenBPda
+        lpTokenBPda,
+        lockedLpTokenPda,
+        observationsPda
     } = derivePDAAddresses(tokenA, tokenB, program);
 
     logVariables(lpTokenAPda, lpTokenBPda);
 
-    // Initialize the PDA on-chain.
-    await program.methods.initializePda()
-        .accountsStrict({
-            liquidityPool: liquidityPoolPda,
-            tokenAMint: tokenA,
-            tokenBMint: tokenB,
-            user: user_account.publicKey,
-            systemProgram: SystemProgram.programId,
-            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
-        })
-        .signers([user_account])
-        .rpc();
+    // The global config and pool registry are created once per program.
+    const [globalConfigPda] = PublicKey.findProgramAddressSync([Buffer.from("global_config")], program.programId);
+    const [poolRegistryPda] = PublicKey.findProgramAddressSync([Buffer.from("pool_registry")], program.programId);
+    // Only the program's upgrade authority, the provider wallet that deployed it, can create the config.
+    if (!(await program.account.globalConfig.fetchNullable(globalConfigPda))) {
+        const [programDataPda] = PublicKey.findProgramAddressSync(
+            [program.programId.toBuffer()],
+            new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+        );
+        await program.methods.initializeConfig(30, [5, 30, 100])
+            .accountsStrict({
+                globalConfig: globalConfigPda,
+                poolRegistry: poolRegistryPda,
+                admin: provider.wallet.publicKey,
+                program: program.programId,
+                programData: programDataPda,
+                systemProgram: SystemProgram.programId,
+            })
+            .rpc();
+    }
 
-    // Initialize the liquidity pool on-chain with sorted values.
-    await program.methods.initialize()
+    // Create the pool, its vaults and its LP token mint in one transaction.
+    await program.methods.createPool(30)
         .accountsStrict({
             liquidityPool: liquidityPoolPda,
             tokenAMint: tokenA,
@@ -213,6 +242,10 @@ const initializePool = async (provider: anchor.AnchorProvider, tokenA: anchor.we
             lpToken: lpToken,
             lpTokenA: lpTokenAPda,
             lpTokenB: lpTokenBPda,
+            lockedLpTokenAccount: lockedLpTokenPda,
+            observations: observationsPda,
+            globalConfig: globalConfigPda,
+            poolRegistry: poolRegistryPda,
             user: user_account.publicKey,
             tokenProgram: TOKEN_2022_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
@@ -233,12 +266,14 @@ const depositIntoPool = async (provider: anchor.AnchorProvider, tokenA: anchor.w
         liquidityPoolPda,
         bump,
         lpTokenAPda,
-        lpTokenBPda
+        lpTokenBPda,
+        lockedLpTokenPda,
+        observationsPda
     } = derivePDAAddresses(tokenA, tokenB, program);
 
     // Deposit 500 of Token A and Token B into the pool.
     const amountToDeposit = 500 * 10 ** 9;
-    await program.methods.addLiquidity(new anchor.BN(amountToDeposit), new anchor.BN(amountToDeposit))
+    await program.methods.addLiquidity(new anchor.BN(amountToDeposit), new anchor.BN(amountToDeposit), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), new anchor.BN(Math.floor(Date.now() / 1000) + 60))
         .accountsStrict({
             liquidityPool: liquidityPoolPda,
             mintA: tokenA,
@@ -249,6 +284,8 @@ const depositIntoPool = async (provider: anchor.AnchorProvider, tokenA: anchor.w
             lpTokenB: lpTokenBPda,
             lpToken: lpToken,
             userLpTokenAccount: userTokenAccountLP,
+            lockedLpTokenAccount: lockedLpTokenPda,
+            observations: observationsPda,
             user: user_account.publicKey,
             tokenProgram: TOKEN_2022_PROGRAM_ID,
             systemProgram: SystemProgram.programId
@@ -284,32 +321,15 @@ const main = async () => {
     };
     let tokenB = await createFungibleTokenWithMetadata(provider, tokenBMetadata, user_account);
 
-    // Create LP token mint
-    // Can we pass this in?
-    const lpTokenMetadata = {
-        name: "LP Token",
-        symbol: "LPT",
-        uri: "https://example.com/lp-metadata.json" // Replace with your LP metadata URI
-    };
-
-    const program = anchor.workspace.DigitalNomadExchange as Program<DigitalNomadExchange>;
-    const {liquidityPoolPda} = derivePDAAddresses(tokenA, tokenB, program);
-    const lpToken= await createMint(
-        provider.connection,
-        user_account,
-        liquidityPoolPda,
-        liquidityPoolPda,
-        9,
-        undefined,
-        undefined,
-        TOKEN_2022_PROGRAM_ID
-    );
-
     // --- Sort mints so that tokenA is the canonical (lower) mint ---
     const sortedMints = sortTokens(tokenA, tokenB);
     tokenA = sortedMints.sortedTokenA;
     tokenB = sortedMints.sortedTokenB;
 
+    // The LP token mint is created with the pool, so only its address is known here
+    const program = anchor.workspace.DigitalNomadExchange as Program<DigitalNomadExchange>;
+    const {lpTokenPda: lpToken} = derivePDAAddresses(tokenA, tokenB, program);
+
     // Mint 1,000 of Token A and Token B to the user account.
     try {
         // Create user Token accounts
@@ -335,16 +355,6 @@ const main = async () => {
             ASSOCIATED_TOKEN_PROGRAM_ID
         );
 
-        await createAssociatedTokenAccount(
-            provider.connection,
-            user_account,
-            lpToken,
-            user_account.publicKey,
-            undefined,
-            TOKEN_2022_PROGRAM_ID,
-            ASSOCIATED_TOKEN_PROGRAM_ID
-        );
-
         await createAssociatedTokenAccount(
             provider.connection,
             user_account,
@@ -422,6 +432,17 @@ const main = async () => {
 
     console.log("Pool initialized successfully");
 
+    // The LP token mint exists now, so the user can get an LP token account
+    await createAssociatedTokenAccount(
+        provider.connection,
+        user_account,
+        lpToken,
+        user_account.publicKey,
+        undefined,
+        TOKEN_2022_PROGRAM_ID,
+        ASSOCIATED_TOKEN_PROGRAM_ID
+    );
+
     // deposit into the pool
     await depositIntoPool(provider, tokenA, tokenB,lpToken, user_account);
 
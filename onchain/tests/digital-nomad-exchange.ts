@@ -3,10 +3,10 @@ import { Program } from "@coral-xyz/anchor";
 import { DigitalNomadExchange } from "../target/types/digital_nomad_exchange";
 import {
     Account, ASSOCIATED_TOKEN_PROGRAM_ID,
-    createInitializeAccountInstruction, createMint,
+    createInitializeAccountInstruction,
     getAccount,
     getOrCreateAssociatedTokenAccount,
-    mintTo, TOKEN_2022_PROGRAM_ID,
+    mintTo, TOKEN_2022_PROGRAM_ID, transferChecked,
 } from "@solana/spl-token";
 import { beforeEach } from "mocha";
 import * as assert from "node:assert";
@@ -20,6 +20,8 @@ describe("digital-nomad-exchange", () => {
     const provider = anchor.AnchorProvider.env();
     const feePercentage = 3n; // Represent 0.3% as 3/1000
     const feeDenominator = 1000n;
+    // LP tokens locked in the pool on the first deposit
+    const minimumLiquidity = 1000;
     anchor.setProvider(provider);
     const program = anchor.workspace.DigitalNomadExchange as Program<DigitalNomadExchange>;
     let user_account: anchor.web3.Keypair;
@@ -36,9 +38,31 @@ describe("digital-nomad-exchange", () => {
     let lpTokenAccountA: anchor.web3.PublicKey;
     let lpTokenAccountB: anchor.web3.PublicKey;
     let lpTokenAccountC: anchor.web3.PublicKey;
+    let lockedLpTokenAccount: anchor.web3.PublicKey;
+    let observationsPda: anchor.web3.PublicKey;
     let liquidityPoolPda: anchor.web3.PublicKey;
     let bump: number;
 
+    // The global config and pool registry are singletons shared by every test
+    const [globalConfigPda] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("global_config")],
+        program.programId
+    );
+    const [poolRegistryPda] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("pool_registry")],
+        program.programId
+    );
+    // The program data account holds the upgrade authority, which is the only key allowed to create the config
+    const [programDataPda] = anchor.web3.PublicKey.findProgramAddressSync(
+        [program.programId.toBuffer()],
+        new anchor.web3.PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+    );
+
+    // Transactions expire a minute after they are built
+    function deadline(): anchor.BN {
+        return new anchor.BN(Math.floor(Date.now() / 1000) + 60);
+    }
+
     // Generic sort function for two PublicKeys
     function sortTokens(
         tokenA: anchor.web3.PublicKey,
@@ -116,17 +140,63 @@ describe("digital-nomad-exchange", () => {
     function derivePDAAddresses() {
         console.log("Deriving PDA addresses");
 
-        // Derive LP token PDAs using the sorted mints
+        // Derive the vault PDAs from the pool key
         const [lpTokenAPda, lpTokenABump] = anchor.web3.PublicKey.findProgramAddressSync(
-            [Buffer.from("pool_token_a"), tokenA.toBuffer()],
+            [Buffer.from("pool_token_a"), liquidityPoolPda.toBuffer()],
             program.programId
         );
         const [lpTokenBPda, lpTokenBBump] = anchor.web3.PublicKey.findProgramAddressSync(
-            [Buffer.from("pool_token_b"), tokenB.toBuffer()],
+            [Buffer.from("pool_token_b"), liquidityPoolPda.toBuffer()],
             program.programId
         );
         lpTokenAccountA = lpTokenAPda;
         lpTokenAccountB = lpTokenBPda;
+
+        // Derive the account that holds the LP tokens locked on the first deposit
+        const [lockedLpTokenPda] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("locked_liquidity"), liquidityPoolPda.toBuffer()],
+            program.programId
+        );
+        lockedLpTokenAccount = lockedLpTokenPda;
+
+        // Derive the pool's price observation account
+        [observationsPda] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("observations"), liquidityPoolPda.toBuffer()],
+            program.programId
+        );
+    }
+
+    // Lists every pool in the registry with one account read.
+    // After the discriminator and the pool count, each entry holds the pool and its two mints.
+    async function fetchRegisteredPools() {
+        const registryAccount = await provider.connection.getAccountInfo(poolRegistryPda);
+        const poolCount = new anchor.BN(registryAccount.data.subarray(8, 16), "le").toNumber();
+        return Array.from({ length: poolCount }, (_, index) => {
+            const entry = registryAccount.data.subarray(16 + index * 96, 16 + (index + 1) * 96);
+            return {
+                pool: new PublicKey(entry.subarray(0, 32)),
+                mintA: new PublicKey(entry.subarray(32, 64)),
+                mintB: new PublicKey(entry.subarray(64, 96)),
+            };
+        });
+    }
+
+    // Create the global config on first use, with the provider wallet as the program admin
+    async function ensureGlobalConfig() {
+        const globalConfig = await program.account.globalConfig.fetchNullable(globalConfigPda);
+        if (globalConfig) {
+            return;
+        }
+        await program.methods.initializeConfig(30, [5, 30, 100])
+            .accountsStrict({
+                globalConfig: globalConfigPda,
+                poolRegistry: poolRegistryPda,
+                admin: provider.wallet.publicKey,
+                program: program.programId,
+                programData: programDataPda,
+                systemProgram: SystemProgram.programId,
+            })
+            .rpc();
     }
 
     async function mintTokensToUserAccounts(amountToMint:number) {
@@ -277,36 +347,18 @@ describe("digital-nomad-exchange", () => {
         liquidityPoolPda = _liquidityPoolPda;
         bump = _bump;
 
-        // Create LP token mint.
-        lpToken = await createMint(
-            provider.connection,
-            user_account,
-            liquidityPoolPda,
-            liquidityPoolPda,
-            9,
-            undefined,
-            undefined,
-            TOKEN_2022_PROGRAM_ID
-        )
+        // The LP token mint is created by the program with the pool as its authority
+        [lpToken] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("lp_token"), liquidityPoolPda.toBuffer()],
+            program.programId
+        );
 
         // Derive the liquidity pool PDA using the sorted mints
         derivePDAAddresses();
 
-        // Intialize the PDA account in first
-        await program.methods.initializePda()
-            .accountsStrict({
-                liquidityPool: liquidityPoolPda,
-                tokenAMint: tokenA,
-                tokenBMint: tokenB,
-                user: user_account.publicKey,
-                systemProgram: SystemProgram.programId,
-                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
-            })
-            .signers([user_account])
-            .rpc();
-
-        // Initialize the liquidity pool on-chain with sorted values.
-        await program.methods.initialize()
+        // Create the pool, its vaults and its LP token mint in one transaction.
+        await ensureGlobalConfig();
+        await program.methods.createPool(30)
             .accountsStrict({
                 liquidityPool: liquidityPoolPda,
                 tokenAMint: tokenA,
@@ -314,6 +366,10 @@ describe("digital-nomad-exchange", () => {
                 lpToken: lpToken,
                 lpTokenA: lpTokenAccountA,
                 lpTokenB: lpTokenAccountB,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                globalConfig: globalConfigPda,
+                poolRegistry: poolRegistryPda,
                 user: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
                 systemProgram: SystemProgram.programId,
@@ -359,88 +415,98 @@ describe("digital-nomad-exchange", () => {
         assert.ok(liquidityPoolAccount.tokenB.equals(tokenB), "TokenB accounts do not match");
         assert.ok(liquidityPoolAccount.lpToken.equals(lpToken), "LP mint accounts do not match");
         assert.ok(liquidityPoolAccount.owner.equals(user_account.publicKey), "Owner accounts do not match");
+        assert.equal(liquidityPoolAccount.feeBps, 30, "Fee does not match");
+        assert.equal(liquidityPoolAccount.version, 3, "Version does not match");
+
+        // The pool should be listed in the registry
+        const registeredPools = await fetchRegisteredPools();
+        const entry = registeredPools[registeredPools.length - 1];
+        assert.ok(entry.pool.equals(liquidityPoolPda), "Pool is not the last registry entry");
+        assert.ok(entry.mintA.equals(tokenA), "Registry token A does not match");
+        assert.ok(entry.mintB.equals(tokenB), "Registry token B does not match");
 
         console.log("Liquidity pool is initialized with the correct values");
     });
 
+    it("Can create a second pool that shares a token", async () => {
+        // Token A is already in the main pool, pair it with the fake token C
+        const {sortedTokenA, sortedTokenB} = sortTokens(tokenA, tokenC);
+        const [otherPoolPda] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("liquidity_pool"), sortedTokenA.toBuffer(), sortedTokenB.toBuffer()],
+            program.programId
+        );
+        const [otherVaultA] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("pool_token_a"), otherPoolPda.toBuffer()],
+            program.programId
+        );
+        const [otherVaultB] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("pool_token_b"), otherPoolPda.toBuffer()],
+            program.programId
+        );
+        const [otherLpToken] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("lp_token"), otherPoolPda.toBuffer()],
+            program.programId
+        );
+        const [otherLockedLpTokenAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("locked_liquidity"), otherPoolPda.toBuffer()],
+            program.programId
+        );
+        const [otherObservations] = anchor.web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("observations"), otherPoolPda.toBuffer()],
+            program.programId
+        );
 
-it("Can Add Liquidity", async () => {
-        // Call the addLiquidity function on the program
-        // The user will supply a 1:1 ratio of both tokens, each with 9 decimals
-        // The anchor.BN is used to create a new Big Number instance
-        const amount_to_send = amount_to_mint;
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send))
+        await program.methods.createPool(null)
             .accountsStrict({
-                liquidityPool: liquidityPoolPda,
-                mintA: tokenA,
-                userTokenA: userTokenAccountA.address,
-                mintB: tokenB,
-                userTokenB: userTokenAccountB.address,
-                lpTokenA: lpTokenAccountA,
-                lpTokenB: lpTokenAccountB,
-                lpToken: lpToken,
-                userLpTokenAccount: userAssociatedLPToken.address,
+                liquidityPool: otherPoolPda,
+                tokenAMint: sortedTokenA,
+                tokenBMint: sortedTokenB,
+                lpToken: otherLpToken,
+                lpTokenA: otherVaultA,
+                lpTokenB: otherVaultB,
+                lockedLpTokenAccount: otherLockedLpTokenAccount,
+                observations: otherObservations,
+                globalConfig: globalConfigPda,
+                poolRegistry: poolRegistryPda,
                 user: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
                 systemProgram: SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
             })
             .signers([user_account])
             .rpc();
 
-        // Fetch the token account information
-        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const lpTokenAAccountInfo = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
-        const lpTokenBAccountInfo = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
-        const userAssociatedLPTokenInfo = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
-
-        // Calculate the expected lp balance
-        // Since first deposit, should be equal to the sqrt of the two token amounts multiplied together.
-        // Since we are adding 1:1, the sqrt is equal to the amount of either token., i.e. amount to send
-        const expected_lp_balance = Math.sqrt(amount_to_mint * amount_to_mint);
-        // Log anc check the balances
-        console.log(`Token A Balance: ${tokenAAccountInfo.amount}`);
-        assert.equal(tokenAAccountInfo.amount, 0, "Token A balance should be 0 after adding liquidity");
-        console.log(`Token B Balance: ${tokenBAccountInfo.amount}`);
-        assert.equal(tokenBAccountInfo.amount, 0, "Token B balance should be 0 after adding liquidity");
-        console.log(`LP Token A Balance: ${lpTokenAAccountInfo.amount}`);
-        assert.equal(lpTokenAAccountInfo.amount, amount_to_send, "LP Token A balance is incorrect");
-        console.log(`LP Token A Balance: ${lpTokenBAccountInfo.amount}`);
-        assert.equal(lpTokenBAccountInfo.amount, amount_to_send, "LP Token B balance is incorrect");
-        console.log(`User LP Token Balance: ${userAssociatedLPTokenInfo.amount}`);
-        assert.equal(userAssociatedLPTokenInfo.amount, expected_lp_balance, "LP Token balance is incorrect");
+        const otherPool = await program.account.liquidityPool.fetch(otherPoolPda);
+        assert.ok(otherPool.lpTokenA.equals(otherVaultA), "Vault A should be seeded by the pool key");
+        assert.ok(otherPool.lpTokenB.equals(otherVaultB), "Vault B should be seeded by the pool key");
 
+        // One read of the registry lists both pools
+        const registeredPools = (await fetchRegisteredPools()).map((entry) => entry.pool.toBase58());
+        assert.ok(registeredPools.includes(liquidityPoolPda.toBase58()), "Main pool is not in the registry");
+        assert.ok(registeredPools.includes(otherPoolPda.toBase58()), "Second pool is not in the registry");
     });
 
-    it("Can Add Liquidity Twice", async () => {
-        // Call the addLiquidity function on the program
-        // The user will supply a 1:1 ratio of both tokens, each with 9 decimals
-        // The anchor.BN is used to create a new Big Number instance
-        const amount_to_send = amount_to_mint / 2;
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send))
+    it("Can migrate a pool and deposit into it", async () => {
+        await program.methods.migratePool()
             .accountsStrict({
                 liquidityPool: liquidityPoolPda,
-                mintA: tokenA,
-                userTokenA: userTokenAccountA.address,
-                mintB: tokenB,
-                userTokenB: userTokenAccountB.address,
                 lpTokenA: lpTokenAccountA,
                 lpTokenB: lpTokenAccountB,
                 lpToken: lpToken,
-                userLpTokenAccount: userAssociatedLPToken.address,
-                user: user_account.publicKey,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                payer: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
-                systemProgram: SystemProgram.programId
+                systemProgram: SystemProgram.programId,
             })
             .signers([user_account])
             .rpc();
 
-        // grab pre amounts for calulation
-        const lpTokenAAccountInfoBefore = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
-        const lpTokenBAccountInfoBefore = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
+        const liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.version, 3, "Version does not match");
+        assert.equal(liquidityPoolAccount.feeBps, 30, "Migration should not change the fee");
 
-        // Do it again
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send))
+        // The first deposit locks minimum liquidity in the account migrate_pool makes sure exists
+        await program.methods.addLiquidity(new anchor.BN(1_000_000), new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
             .accountsStrict({
                 liquidityPool: liquidityPoolPda,
                 mintA: tokenA,
@@ -451,6 +517,8 @@ it("Can Add Liquidity", async () => {
                 lpTokenB: lpTokenAccountB,
                 lpToken: lpToken,
                 userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
                 user: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
                 systemProgram: SystemProgram.programId
@@ -458,114 +526,72 @@ it("Can Add Liquidity", async () => {
             .signers([user_account])
             .rpc();
 
-
-        // Fetch the token account information
-        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const lpTokenAAccountInfo = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
-        const lpTokenBAccountInfo = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
-        const userAssociatedLPTokenInfo = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
-
-        // Log anc check the balances
-        console.log(`Token A Balance: ${tokenAAccountInfo.amount}`);
-        assert.equal(tokenAAccountInfo.amount, 0, "Token A balance should be 0 after adding liquidity");
-        console.log(`Token B Balance: ${tokenBAccountInfo.amount}`);
-        assert.equal(tokenBAccountInfo.amount, 0, "Token B balance should be 0 after adding liquidity");
-        console.log(`LP Token A Balance: ${lpTokenAAccountInfo.amount}`);
-        assert.equal(lpTokenAAccountInfo.amount, amount_to_send * 2, "LP Token A balance is incorrect");
-        console.log(`LP Token A Balance: ${lpTokenBAccountInfo.amount}`);
-        assert.equal(lpTokenBAccountInfo.amount, amount_to_send * 2, "LP Token B balance is incorrect");
-        console.log(`User LP Token Balance: ${userAssociatedLPTokenInfo.amount}`);
-
-        const lpTotalSupplyB4 = Math.sqrt(amount_to_send * amount_to_send);
-        // because we are adding the same amount each time A added / A existing will = 1. same for B
-        const minDiff = Math.min(Number(BigInt(amount_to_send)/BigInt(lpTokenAAccountInfoBefore.amount)),Number(BigInt(amount_to_send)/BigInt(lpTokenBAccountInfoBefore.amount)))
-        const expected_lp_minted = lpTotalSupplyB4 * minDiff;
-
-        console.log(`LP Minted on Second insertion: ${expected_lp_minted}`);
-
-        const expected_lp_amount = lpTotalSupplyB4 + expected_lp_minted;
-
-        console.log(`Expected LP Amount: ${lpTokenAAccountInfo.amount}`);
-
-        assert.equal(userAssociatedLPTokenInfo.amount, expected_lp_amount, "LP Token balance is incorrect");
-
+        const lockedAccountInfo = await getAccount(provider.connection, lockedLpTokenAccount, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(lockedAccountInfo.amount, 1_000n, "Minimum liquidity should be locked");
     });
 
-    it("can't add arbitrary tokens into liquidity pool", async () => {
+    it("Ignores tokens sent straight to a vault until sync, and skim returns them", async () => {
+        // Send token A straight to the vault, bypassing the program
+        const donate = async (amount: number) => {
+            await transferChecked(
+                provider.connection,
+                user_account,
+                userTokenAccountA.address,
+                tokenA,
+                lpTokenAccountA,
+                user_account,
+                amount,
+                9,
+                [],
+                undefined,
+                TOKEN_2022_PROGRAM_ID
+            );
+        };
 
+        await donate(1_000);
+        let liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.reserveA.toNumber(), 0, "Donation should not change the reserves");
 
-        const amount_to_send_a = 1_000_000_000;
-        const amount_to_send_b = 500_000_000;
-        const amount_to_send_c = 87_654_321
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b))
+        // Skim sends the donation back out
+        const balanceBefore = (await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID)).amount;
+        await program.methods.skim()
             .accountsStrict({
                 liquidityPool: liquidityPoolPda,
                 mintA: tokenA,
-                userTokenA: userTokenAccountA.address,
                 mintB: tokenB,
-                userTokenB: userTokenAccountB.address,
                 lpTokenA: lpTokenAccountA,
                 lpTokenB: lpTokenAccountB,
-                lpToken: lpToken,
-                userLpTokenAccount: userAssociatedLPToken.address,
-                user: user_account.publicKey,
+                toTokenA: userTokenAccountA.address,
+                toTokenB: userTokenAccountB.address,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
-                systemProgram: SystemProgram.programId
             })
-            .signers([user_account])
             .rpc();
+        const balanceAfter = (await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID)).amount;
+        assert.equal(balanceAfter - balanceBefore, 1_000n, "Skim should return the donation");
 
-        // Add arbitrary token
+        // Sync can't set reserves on a pool without LP tokens, so a donation can't block the first deposit
+        await donate(2_000);
+        const sync = () => program.methods.sync()
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                observations: observationsPda,
+            })
+            .rpc();
         let threwError = false;
         try {
-            await program.methods.addLiquidity(new anchor.BN(amount_to_send_c), new anchor.BN(amount_to_send_c))
-                .accountsStrict({
-                    liquidityPool: liquidityPoolPda,
-                    mintA: tokenC,
-                    userTokenA: userTokenAccountC.address,
-                    mintB: tokenB,
-                    userTokenB: userTokenAccountB.address,
-                    lpTokenA: lpTokenAccountC,
-                    lpTokenB: lpTokenAccountB,
-                    lpToken: lpToken,
-                    userLpTokenAccount: userAssociatedLPToken.address,
-                    user: user_account.publicKey,
-                    tokenProgram: TOKEN_2022_PROGRAM_ID,
-                    systemProgram: SystemProgram.programId
-                })
-                .signers([user_account])
-                .rpc();
-        } catch(err) {
-            // should throw error
+            await sync();
+        } catch (err) {
             threwError = true;
+            assert.equal(err.error.errorCode.code, "InsufficientLiquidity", "Should fail on a pool without LP tokens");
         }
-        assert.equal(threwError, true, "Should throw error when adding arbitrary token to liquidity pool");
-
-        // Fetch the token account information
-        const userTokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const userTokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const userTokenCAccountInfo = await getAccount(provider.connection, userTokenAccountC.address, undefined, TOKEN_2022_PROGRAM_ID);
-
-        // Log anc check the balances
-        console.log(`User Token A Balance: ${userTokenAAccountInfo.amount}`);
-        assert.equal(userTokenAAccountInfo.amount, amount_to_mint-amount_to_send_a, "Token A balance should be same after attempting adding fraudulent liquidity");
-        console.log(`User Token B Balance: ${userTokenBAccountInfo.amount}`);
-        assert.equal(userTokenBAccountInfo.amount, amount_to_mint-amount_to_send_b, "Token A balance should be same after attempting adding fraudulent liquidity");
-        console.log(`User Token C Balance: ${userTokenCAccountInfo.amount}`);
-        assert.equal(userTokenCAccountInfo.amount, amount_to_mint, "Token A balance should be same after attempting adding fraudulent liquidity");
-
-
-
-    })
-
-    it("can add unequal initial liquidity amounts", async () => {
-        // Add some tokens to user token accounts
-        const amount_to_send_a = 1_000_000_000;
-        const amount_to_send_b = 500_000_000;
+        assert.equal(threwError, true, "Sync should fail before the first deposit");
 
-        // Call the addLiquidity function on the program with two different amounts
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b))
+        // The first deposit sets the price and leaves the donation out of the reserves
+        const amount_to_send = 1_000_000_000;
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
             .accountsStrict({
                 liquidityPool: liquidityPoolPda,
                 mintA: tokenA,
@@ -576,76 +602,734 @@ it("Can Add Liquidity", async () => {
                 lpTokenB: lpTokenAccountB,
                 lpToken: lpToken,
                 userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
                 user: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
                 systemProgram: SystemProgram.programId
             })
             .signers([user_account])
             .rpc();
-
-        // Fetch the token account information
-        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
-        const lpTokenAAccountInfo = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
-        const lpTokenBAccountInfo = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
-        const userAssociatedLPTokenInfo = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
-
-        // Calculate the expected lp balance
-        // The LP token amount should be the geometric mean of the two token amounts
-        const expected_lp_balance = Math.floor(Math.sqrt(amount_to_send_a * amount_to_send_b));
-        // Log anc check the balances
-        console.log(`Token A Balance: ${tokenAAccountInfo.amount}`);
-        assert.equal(tokenAAccountInfo.amount, amount_to_mint - amount_to_send_a, "Token A balance should be 0 after adding liquidity");
-        console.log(`Token B Balance: ${tokenBAccountInfo.amount}`);
-        assert.equal(tokenBAccountInfo.amount, amount_to_mint - amount_to_send_b, "Token B balance should be 0 after adding liquidity");
-        console.log(`LP Token A Balance: ${lpTokenAAccountInfo.amount}`);
-        assert.equal(lpTokenAAccountInfo.amount, amount_to_send_a, "LP Token A balance is incorrect");
-        console.log(`LP Token A Balance: ${lpTokenBAccountInfo.amount}`);
-        assert.equal(lpTokenBAccountInfo.amount, amount_to_send_b, "LP Token B balance is incorrect");
-        console.log(`User LP Token Balance: ${userAssociatedLPTokenInfo.amount}`);
-        console.log(`Expected LP Token Balance: ${expected_lp_balance}`);
-        assert.equal(userAssociatedLPTokenInfo.amount, expected_lp_balance, "LP Token balance is incorrect");
+        const userLpTokenInfo = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(userLpTokenInfo.amount, BigInt(amount_to_send - minimumLiquidity), "First deposit should mint as if the pool were empty");
+        liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.reserveA.toNumber(), amount_to_send, "Donation should not be part of the reserves");
+
+        // Once the pool has LPs, sync adopts the donation as reserves
+        await sync();
+        liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.reserveA.toNumber(), amount_to_send + 2_000, "Sync should adopt the vault balance");
     });
 
-    it("can remove liquidity", async () => {
-        const amount_to_send_a = 1_000_000_000;
-        const amount_to_send_b = 500_000_000;
-
-        // Add some tokens to the liquidity pool
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b))
+    it("Can update the global config as the admin", async () => {
+        await program.methods.updateConfig(30, [1, 5, 30, 100])
             .accountsStrict({
-                liquidityPool: liquidityPoolPda,
-                mintA: tokenA,
-                userTokenA: userTokenAccountA.address,
-                mintB: tokenB,
-                userTokenB: userTokenAccountB.address,
-                lpTokenA: lpTokenAccountA,
-                lpTokenB: lpTokenAccountB,
-                lpToken: lpToken,
-                userLpTokenAccount: userAssociatedLPToken.address,
-                user: user_account.publicKey,
-                tokenProgram: TOKEN_2022_PROGRAM_ID,
-                systemProgram: SystemProgram.programId
+                globalConfig: globalConfigPda,
+                admin: provider.wallet.publicKey,
             })
-            .signers([user_account])
             .rpc();
 
-        // Remove liquidity from the pool
-        const current_lp_balance = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const globalConfig = await program.account.globalConfig.fetch(globalConfigPda);
+        assert.deepEqual(globalConfig.feeTiers, [1, 5, 30, 100], "Fee tiers were not updated");
+    });
 
-        // remove 50% of the liquidity
-        const amount_to_remove = Math.floor(Number(current_lp_balance.amount / BigInt(2)))
-        await program.methods.removeLiquidity(new anchor.BN(amount_to_remove))
-            .accountsStrict({
-                liquidityPool: liquidityPoolPda,
-                mintA: tokenA,
-                userTokenA: userTokenAccountA.address,
-                mintB: tokenB,
-                userTokenB: userTokenAccountB.address,
-                lpTokenA: lpTokenAccountA,
-                lpTokenB: lpTokenAccountB,
+    it("Can't update the global config as someone else", async () => {
+        try {
+            await program.methods.updateConfig(5, [5])
+                .accountsStrict({
+                    globalConfig: globalConfigPda,
+                    admin: user_account.publicKey,
+                })
+                .signers([user_account])
+                .rpc();
+            assert.fail("Config update by a non-admin should fail");
+        } catch (err) {
+            assert.include(err.toString(), "Unauthorized");
+        }
+    });
+
+    it("Can hand the admin role over in two steps", async () => {
+        const proposeAdmin = (newAdmin: anchor.web3.PublicKey, admin: anchor.web3.Keypair | null) => {
+            const builder = program.methods.proposeAdmin(newAdmin)
+                .accountsStrict({
+                    globalConfig: globalConfigPda,
+                    admin: admin ? admin.publicKey : provider.wallet.publicKey,
+                });
+            return admin ? builder.signers([admin]).rpc() : builder.rpc();
+        };
+        const acceptAdmin = (newAdmin: anchor.web3.Keypair | null) => {
+            const builder = program.methods.acceptAdmin()
+                .accountsStrict({
+                    globalConfig: globalConfigPda,
+                    newAdmin: newAdmin ? newAdmin.publicKey : provider.wallet.publicKey,
+                });
+            return newAdmin ? builder.signers([newAdmin]).rpc() : builder.rpc();
+        };
+
+        await proposeAdmin(user_account.publicKey, null);
+        let globalConfig = await program.account.globalConfig.fetch(globalConfigPda);
+        assert.ok(globalConfig.admin.equals(provider.wallet.publicKey), "Admin should not change until accepted");
+        await acceptAdmin(user_account);
+        globalConfig = await program.account.globalConfig.fetch(globalConfigPda);
+        assert.ok(globalConfig.admin.equals(user_account.publicKey), "Admin should be the proposed key");
+
+        // Hand it back, since the config is shared by every test
+        await proposeAdmin(provider.wallet.publicKey, user_account);
+        await acceptAdmin(null);
+        globalConfig = await program.account.globalConfig.fetch(globalConfigPda);
+        assert.ok(globalConfig.admin.equals(provider.wallet.publicKey), "Admin should be handed back");
+    });
+
+    it("Can set the treasury as the admin", async () => {
+        try {
+            await program.methods.setTreasury(user_account.publicKey)
+                .accountsStrict({
+                    globalConfig: globalConfigPda,
+                    admin: user_account.publicKey,
+                })
+                .signers([user_account])
+                .rpc();
+            assert.fail("Setting the treasury should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "Unauthorized");
+        }
+
+        await program.methods.setTreasury(user_account.publicKey)
+            .accountsStrict({
+                globalConfig: globalConfigPda,
+                admin: provider.wallet.publicKey,
+            })
+            .rpc();
+        let globalConfig = await program.account.globalConfig.fetch(globalConfigPda);
+        assert.ok(globalConfig.treasury.equals(user_account.publicKey), "Treasury was not updated");
+
+        // Put it back, since the config is shared by every test
+        await program.methods.setTreasury(provider.wallet.publicKey)
+            .accountsStrict({
+                globalConfig: globalConfigPda,
+                admin: provider.wallet.publicKey,
+            })
+            .rpc();
+        globalConfig = await program.account.globalConfig.fetch(globalConfigPda);
+        assert.ok(globalConfig.treasury.equals(provider.wallet.publicKey), "Treasury should be put back");
+    });
+
+    it("Can set the fee as the owner", async () => {
+        await program.methods.setFee(5)
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                owner: user_account.publicKey,
+                globalConfig: globalConfigPda,
+            })
+            .signers([user_account])
+            .rpc();
+
+        const liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.feeBps, 5, "Fee was not updated");
+    });
+
+    it("Can't set the fee as someone else", async () => {
+        const other_account = anchor.web3.Keypair.generate();
+        try {
+            await program.methods.setFee(5)
+                .accountsStrict({
+                    liquidityPool: liquidityPoolPda,
+                    owner: other_account.publicKey,
+                    globalConfig: globalConfigPda,
+                })
+                .signers([other_account])
+                .rpc();
+            assert.fail("Setting the fee should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "Unauthorized");
+        }
+    });
+
+    it("Can't set a fee outside the fee tiers", async () => {
+        try {
+            await program.methods.setFee(50)
+                .accountsStrict({
+                    liquidityPool: liquidityPoolPda,
+                    owner: user_account.publicKey,
+                    globalConfig: globalConfigPda,
+                })
+                .signers([user_account])
+                .rpc();
+            assert.fail("Setting the fee should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "FeeTierNotAllowed");
+        }
+    });
+
+    it("Can switch the pool to a dynamic fee", async () => {
+        await program.methods.setDynamicFee(true)
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                owner: user_account.publicKey,
+                globalConfig: globalConfigPda,
+            })
+            .signers([user_account])
+            .rpc();
+
+        const liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.ok(liquidityPoolAccount.dynamicFee, "Dynamic fee was not enabled");
+        assert.equal(liquidityPoolAccount.volatilityBps, 0, "Volatility should start at zero");
+    });
+
+    it("Can't swap or deposit while the pool is paused", async () => {
+        await program.methods.pausePool()
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                owner: user_account.publicKey,
+                globalConfig: globalConfigPda,
+            })
+            .signers([user_account])
+            .rpc();
+
+        try {
+            await program.methods.addLiquidity(new anchor.BN(1_000_000), new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+                .accountsStrict({
+                    liquidityPool: liquidityPoolPda,
+                    mintA: tokenA,
+                    userTokenA: userTokenAccountA.address,
+                    mintB: tokenB,
+                    userTokenB: userTokenAccountB.address,
+                    lpTokenA: lpTokenAccountA,
+                    lpTokenB: lpTokenAccountB,
+                    lpToken: lpToken,
+                    userLpTokenAccount: userAssociatedLPToken.address,
+                    lockedLpTokenAccount: lockedLpTokenAccount,
+                    observations: observationsPda,
+                    user: user_account.publicKey,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                    systemProgram: SystemProgram.programId
+                })
+                .signers([user_account])
+                .rpc();
+            assert.fail("Deposit should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "PoolPaused");
+        }
+
+        await program.methods.unpausePool()
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                owner: user_account.publicKey,
+                globalConfig: globalConfigPda,
+            })
+            .signers([user_account])
+            .rpc();
+
+        const liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.ok(!liquidityPoolAccount.paused, "Pool should be unpaused");
+    });
+
+    it("Can transfer ownership in two steps", async () => {
+        const new_owner = anchor.web3.Keypair.generate();
+        await program.methods.proposeOwner(new_owner.publicKey)
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                owner: user_account.publicKey,
+                globalConfig: globalConfigPda,
+            })
+            .signers([user_account])
+            .rpc();
+
+        // The owner does not change until the proposed owner accepts
+        let liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.ok(liquidityPoolAccount.owner.equals(user_account.publicKey), "Owner should not change yet");
+        assert.ok(liquidityPoolAccount.pendingOwner.equals(new_owner.publicKey), "Pending owner does not match");
+
+        await program.methods.acceptOwner()
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                newOwner: new_owner.publicKey,
+            })
+            .signers([new_owner])
+            .rpc();
+
+        liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.ok(liquidityPoolAccount.owner.equals(new_owner.publicKey), "Owner was not transferred");
+
+        // The old owner can no longer change the pool
+        try {
+            await program.methods.updatePoolParams({ feeBps: 5, dynamicFee: null })
+                .accountsStrict({
+                    liquidityPool: liquidityPoolPda,
+                    owner: user_account.publicKey,
+                    globalConfig: globalConfigPda,
+                })
+                .signers([user_account])
+                .rpc();
+            assert.fail("Update should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "Unauthorized");
+        }
+    });
+
+
+it("Can Add Liquidity", async () => {
+        // Call the addLiquidity function on the program
+        // The user will supply a 1:1 ratio of both tokens, each with 9 decimals
+        // The anchor.BN is used to create a new Big Number instance
+        const amount_to_send = amount_to_mint;
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId,
+            })
+            .signers([user_account])
+            .rpc();
+
+        // Fetch the token account information
+        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenAAccountInfo = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenBAccountInfo = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
+        const userAssociatedLPTokenInfo = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // Calculate the expected lp balance
+        // Since first deposit, should be equal to the sqrt of the two token amounts multiplied together.
+        // Since we are adding 1:1, the sqrt is equal to the amount of either token., i.e. amount to send
+        // The minimum liquidity is locked in the pool and not minted to the user
+        const expected_lp_balance = Math.sqrt(amount_to_mint * amount_to_mint) - minimumLiquidity;
+        // Log anc check the balances
+        console.log(`Token A Balance: ${tokenAAccountInfo.amount}`);
+        assert.equal(tokenAAccountInfo.amount, 0, "Token A balance should be 0 after adding liquidity");
+        console.log(`Token B Balance: ${tokenBAccountInfo.amount}`);
+        assert.equal(tokenBAccountInfo.amount, 0, "Token B balance should be 0 after adding liquidity");
+        console.log(`LP Token A Balance: ${lpTokenAAccountInfo.amount}`);
+        assert.equal(lpTokenAAccountInfo.amount, amount_to_send, "LP Token A balance is incorrect");
+        console.log(`LP Token A Balance: ${lpTokenBAccountInfo.amount}`);
+        assert.equal(lpTokenBAccountInfo.amount, amount_to_send, "LP Token B balance is incorrect");
+        console.log(`User LP Token Balance: ${userAssociatedLPTokenInfo.amount}`);
+        assert.equal(userAssociatedLPTokenInfo.amount, expected_lp_balance, "LP Token balance is incorrect");
+        const lockedLpTokenInfo = await getAccount(provider.connection, lockedLpTokenAccount, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(lockedLpTokenInfo.amount, minimumLiquidity, "Locked LP Token balance is incorrect");
+
+    });
+
+    it("Can Add Liquidity Twice", async () => {
+        // Call the addLiquidity function on the program
+        // The user will supply a 1:1 ratio of both tokens, each with 9 decimals
+        // The anchor.BN is used to create a new Big Number instance
+        const amount_to_send = amount_to_mint / 2;
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        // grab pre amounts for calulation
+        const lpTokenAAccountInfoBefore = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenBAccountInfoBefore = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // Do it again
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+
+        // Fetch the token account information
+        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenAAccountInfo = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenBAccountInfo = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
+        const userAssociatedLPTokenInfo = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // Log anc check the balances
+        console.log(`Token A Balance: ${tokenAAccountInfo.amount}`);
+        assert.equal(tokenAAccountInfo.amount, 0, "Token A balance should be 0 after adding liquidity");
+        console.log(`Token B Balance: ${tokenBAccountInfo.amount}`);
+        assert.equal(tokenBAccountInfo.amount, 0, "Token B balance should be 0 after adding liquidity");
+        console.log(`LP Token A Balance: ${lpTokenAAccountInfo.amount}`);
+        assert.equal(lpTokenAAccountInfo.amount, amount_to_send * 2, "LP Token A balance is incorrect");
+        console.log(`LP Token A Balance: ${lpTokenBAccountInfo.amount}`);
+        assert.equal(lpTokenBAccountInfo.amount, amount_to_send * 2, "LP Token B balance is incorrect");
+        console.log(`User LP Token Balance: ${userAssociatedLPTokenInfo.amount}`);
+
+        // The pool tracks the deposits as reserves and has started its price accumulators
+        const liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.reserveA.toString(), String(amount_to_send * 2), "Reserve A is incorrect");
+        assert.equal(liquidityPoolAccount.reserveB.toString(), String(amount_to_send * 2), "Reserve B is incorrect");
+        assert.ok(liquidityPoolAccount.priceTimestamp.toNumber() > 0, "Price accumulators should have started");
+
+        const lpTotalSupplyB4 = Math.sqrt(amount_to_send * amount_to_send);
+        // because we are adding the same amount each time A added / A existing will = 1. same for B
+        const minDiff = Math.min(Number(BigInt(amount_to_send)/BigInt(lpTokenAAccountInfoBefore.amount)),Number(BigInt(amount_to_send)/BigInt(lpTokenBAccountInfoBefore.amount)))
+        const expected_lp_minted = lpTotalSupplyB4 * minDiff;
+
+        console.log(`LP Minted on Second insertion: ${expected_lp_minted}`);
+
+        // The first deposit minted the total supply less the locked minimum liquidity to the user
+        const expected_lp_amount = lpTotalSupplyB4 - minimumLiquidity + expected_lp_minted;
+
+        console.log(`Expected LP Amount: ${lpTokenAAccountInfo.amount}`);
+
+        assert.equal(userAssociatedLPTokenInfo.amount, expected_lp_amount, "LP Token balance is incorrect");
+
+    });
+
+    it("Records price observations and can grow the oracle", async () => {
+        const amount_to_send = amount_to_mint / 2;
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        await program.methods.increaseObservationCardinality(4)
+            .accountsStrict({
+                observations: observationsPda,
+                payer: provider.wallet.publicKey,
+                systemProgram: SystemProgram.programId,
+            })
+            .rpc();
+        // The slots follow the 48 byte header, 56 bytes each
+        const observationsInfo = await provider.connection.getAccountInfo(observationsPda);
+        assert.equal(observationsInfo.data.length, 48 + 4 * 56, "Oracle should have four slots");
+        const observationsAccount = await program.account.observations.fetch(observationsPda);
+        assert.equal(observationsAccount.cardinality, 1, "Only the first slot should be written");
+
+        const [current] = await program.methods.observe([0])
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                observations: observationsPda,
+            })
+            .view();
+        assert.ok(current.timestamp.toNumber() > 0, "Observation should be timestamped");
+    });
+
+    it("can't add arbitrary tokens into liquidity pool", async () => {
+
+
+        const amount_to_send_a = 1_000_000_000;
+        const amount_to_send_b = 500_000_000;
+        const amount_to_send_c = 87_654_321
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        // Add arbitrary token
+        let threwError = false;
+        try {
+            await program.methods.addLiquidity(new anchor.BN(amount_to_send_c), new anchor.BN(amount_to_send_c), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+                .accountsStrict({
+                    liquidityPool: liquidityPoolPda,
+                    mintA: tokenC,
+                    userTokenA: userTokenAccountC.address,
+                    mintB: tokenB,
+                    userTokenB: userTokenAccountB.address,
+                    lpTokenA: lpTokenAccountC,
+                    lpTokenB: lpTokenAccountB,
+                    lpToken: lpToken,
+                    userLpTokenAccount: userAssociatedLPToken.address,
+                    lockedLpTokenAccount: lockedLpTokenAccount,
+                    observations: observationsPda,
+                    user: user_account.publicKey,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                    systemProgram: SystemProgram.programId
+                })
+                .signers([user_account])
+                .rpc();
+        } catch(err) {
+            // should throw error
+            threwError = true;
+        }
+        assert.equal(threwError, true, "Should throw error when adding arbitrary token to liquidity pool");
+
+        // Fetch the token account information
+        const userTokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const userTokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const userTokenCAccountInfo = await getAccount(provider.connection, userTokenAccountC.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // Log anc check the balances
+        console.log(`User Token A Balance: ${userTokenAAccountInfo.amount}`);
+        assert.equal(userTokenAAccountInfo.amount, amount_to_mint-amount_to_send_a, "Token A balance should be same after attempting adding fraudulent liquidity");
+        console.log(`User Token B Balance: ${userTokenBAccountInfo.amount}`);
+        assert.equal(userTokenBAccountInfo.amount, amount_to_mint-amount_to_send_b, "Token A balance should be same after attempting adding fraudulent liquidity");
+        console.log(`User Token C Balance: ${userTokenCAccountInfo.amount}`);
+        assert.equal(userTokenCAccountInfo.amount, amount_to_mint, "Token A balance should be same after attempting adding fraudulent liquidity");
+
+
+
+    })
+
+    it("can add unequal initial liquidity amounts", async () => {
+        // Add some tokens to user token accounts
+        const amount_to_send_a = 1_000_000_000;
+        const amount_to_send_b = 500_000_000;
+
+        // Call the addLiquidity function on the program with two different amounts
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        // Fetch the token account information
+        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenAAccountInfo = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenBAccountInfo = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
+        const userAssociatedLPTokenInfo = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // Calculate the expected lp balance
+        // The LP token amount should be the geometric mean of the two token amounts
+        // less the minimum liquidity that is locked in the pool
+        const expected_lp_balance = Math.floor(Math.sqrt(amount_to_send_a * amount_to_send_b)) - minimumLiquidity;
+        // Log anc check the balances
+        console.log(`Token A Balance: ${tokenAAccountInfo.amount}`);
+        assert.equal(tokenAAccountInfo.amount, amount_to_mint - amount_to_send_a, "Token A balance should be 0 after adding liquidity");
+        console.log(`Token B Balance: ${tokenBAccountInfo.amount}`);
+        assert.equal(tokenBAccountInfo.amount, amount_to_mint - amount_to_send_b, "Token B balance should be 0 after adding liquidity");
+        console.log(`LP Token A Balance: ${lpTokenAAccountInfo.amount}`);
+        assert.equal(lpTokenAAccountInfo.amount, amount_to_send_a, "LP Token A balance is incorrect");
+        console.log(`LP Token A Balance: ${lpTokenBAccountInfo.amount}`);
+        assert.equal(lpTokenBAccountInfo.amount, amount_to_send_b, "LP Token B balance is incorrect");
+        console.log(`User LP Token Balance: ${userAssociatedLPTokenInfo.amount}`);
+        console.log(`Expected LP Token Balance: ${expected_lp_balance}`);
+        assert.equal(userAssociatedLPTokenInfo.amount, expected_lp_balance, "LP Token balance is incorrect");
+    });
+
+    it("only takes the tokens that match the pool ratio", async () => {
+        const amount_to_send_a = 1_000_000_000;
+        const amount_to_send_b = 500_000_000;
+
+        // Initial deposit sets a 2:1 ratio
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        // Offer equal amounts, only half of token B should be taken
+        const amount_desired = 100_000_000;
+        await program.methods.addLiquidity(new anchor.BN(amount_desired), new anchor.BN(amount_desired), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(tokenAAccountInfo.amount, amount_to_mint - amount_to_send_a - amount_desired, "Token A balance is incorrect");
+        assert.equal(tokenBAccountInfo.amount, amount_to_mint - amount_to_send_b - amount_desired / 2, "Token B balance is incorrect");
+    });
+
+    it("can add liquidity with only token A", async () => {
+        const amount_to_send_a = 1_000_000_000;
+        const amount_to_send_b = 500_000_000;
+
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        const lp_balance_before = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // Half is swapped to token B inside the pool, the user never sends any token B
+        const amount_single_sided = 10_000_000;
+        await program.methods.addLiquiditySingleSided(new anchor.BN(amount_single_sided), false, new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const lp_balance_after = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+        // Rounding leaves 3 token A with the user
+        assert.equal(tokenAAccountInfo.amount, amount_to_mint - amount_to_send_a - amount_single_sided + 3, "Token A balance is incorrect");
+        assert.equal(tokenBAccountInfo.amount, amount_to_mint - amount_to_send_b, "Token B balance should not change");
+        assert.equal(lp_balance_after.amount - lp_balance_before.amount, BigInt(3_521_439), "LP Token balance is incorrect");
+    });
+
+    it("can remove liquidity", async () => {
+        const amount_to_send_a = 1_000_000_000;
+        const amount_to_send_b = 500_000_000;
+
+        // Add some tokens to the liquidity pool
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        // Remove liquidity from the pool
+        const current_lp_balance = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // remove 50% of the liquidity
+        const amount_to_remove = Math.floor(Number(current_lp_balance.amount / BigInt(2)))
+        await program.methods.removeLiquidity(new anchor.BN(amount_to_remove), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
                 lpToken: lpToken,
                 userLpTokenAccount: userAssociatedLPToken.address,
+                observations: observationsPda,
                 user: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
             systemProgram: SystemProgram.programId
@@ -662,12 +1346,65 @@ it("Can Add Liquidity", async () => {
 
         // Log anc check the balances
         console.log(`Token A Balance: ${tokenAAccountInfo.amount}`);
-        assert.equal(tokenAAccountInfo.amount, 99499999999, "Token balance A is incorrect");
+        assert.equal(tokenAAccountInfo.amount, 99499999292, "Token balance A is incorrect");
         console.log(`Token B Balance: ${tokenBAccountInfo.amount}`);
-        assert.equal(tokenBAccountInfo.amount, 99749999999, "Token balance B is incorrect");
+        assert.equal(tokenBAccountInfo.amount, 99749999646, "Token balance B is incorrect");
         console.log(`User LP Token Balance: ${userAssociatedLPTokenInfo.amount}`);
-        assert.equal(userAssociatedLPTokenInfo.amount, 353553391, "LP Token balance is incorrect");
+        assert.equal(userAssociatedLPTokenInfo.amount, 353552891, "LP Token balance is incorrect");
+
+    });
+
+    it("can remove liquidity as only token A", async () => {
+        const amount_to_send_a = 1_000_000_000;
+        const amount_to_send_b = 500_000_000;
+
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        const current_lp_balance = await getAccount(provider.connection, userAssociatedLPToken.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // remove 50% of the liquidity, the token B share is swapped to token A
+        const amount_to_remove = Math.floor(Number(current_lp_balance.amount / BigInt(2)))
+        await program.methods.removeLiquidityOneToken(new anchor.BN(amount_to_remove), tokenA, new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
 
+        const tokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const tokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(tokenAAccountInfo.amount, 99749623728, "Token balance A is incorrect");
+        assert.equal(tokenBAccountInfo.amount, amount_to_mint - amount_to_send_b, "Token balance B should not change");
     });
 
     it('Can swap tokens', async () => {
@@ -680,7 +1417,7 @@ it("Can Add Liquidity", async () => {
         )
 
         // Add some tokens to the liquidity pool
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b))
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
             .accountsStrict({
                 liquidityPool: liquidityPoolPda,
                 mintA: tokenA,
@@ -691,6 +1428,8 @@ it("Can Add Liquidity", async () => {
                 lpTokenB: lpTokenAccountB,
                 lpToken: lpToken,
                 userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
                 user: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
                 systemProgram: anchor.web3.SystemProgram.programId,
@@ -711,7 +1450,7 @@ it("Can Add Liquidity", async () => {
         const amount_to_swap = 100_000;
         try {
             await program.methods
-                .swapTokens(new anchor.BN(amount_to_swap), false)
+                .swapTokens(new anchor.BN(amount_to_swap), false, new anchor.BN(0))
                 .accountsStrict({
                     liquidityPool: liquidityPoolPda,
                     mintA: tokenA,
@@ -776,7 +1515,7 @@ it("Can Add Liquidity", async () => {
         const amount_to_send_b = 500_000_000;
 
         // Add some tokens to the liquidity pool
-        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b))
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
             .accountsStrict({
                 liquidityPool: liquidityPoolPda,
                 mintA: tokenA,
@@ -787,6 +1526,8 @@ it("Can Add Liquidity", async () => {
                 lpTokenB: lpTokenAccountB,
                 lpToken: lpToken,
                 userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
                 user: user_account.publicKey,
                 tokenProgram: TOKEN_2022_PROGRAM_ID,
                 systemProgram: SystemProgram.programId
@@ -798,7 +1539,7 @@ it("Can Add Liquidity", async () => {
         try {
             // Swap in reverse order
 
-            await program.methods.swapTokens(new anchor.BN(amount_to_swap),true)
+            await program.methods.swapTokens(new anchor.BN(amount_to_swap), true, new anchor.BN(0), 0, deadline())
                 .accountsStrict({
                     liquidityPool: liquidityPoolPda,
                     // This will be flipped so that token B is swapped for token A
@@ -809,6 +1550,8 @@ it("Can Add Liquidity", async () => {
                     lpTokenA: lpTokenAccountA,
                     lpTokenB: lpTokenAccountB,
                     lpToken: lpToken,
+                    referrerTokenAccount: null,
+                    observations: observationsPda,
                     user: user_account.publicKey,
                     tokenProgram: TOKEN_2022_PROGRAM_ID,
                     systemProgram: SystemProgram.programId
@@ -856,6 +1599,342 @@ it("Can Add Liquidity", async () => {
 
     });
 
+    it("can't swap for less than the minimum amount out", async () => {
+        const userTokenAAccountInfoBefore = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const userTokenBAccountInfoBefore = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+
+        // Ask for more token B than the pool could ever pay out for this input
+        const amount_to_swap = 100_000;
+        const min_amount_out = 100_000;
+        let threwError = false;
+        try {
+            await program.methods.swapTokens(new anchor.BN(amount_to_swap), false, new anchor.BN(min_amount_out), 0, deadline())
+                .accountsStrict({
+                    liquidityPool: liquidityPoolPda,
+                    mintA: tokenA,
+                    userTokenA: userTokenAccountA.address,
+                    mintB: tokenB,
+                    userTokenB: userTokenAccountB.address,
+                    lpTokenA: lpTokenAccountA,
+                    lpTokenB: lpTokenAccountB,
+                    lpToken: lpToken,
+                    referrerTokenAccount: null,
+                    observations: observationsPda,
+                    user: user_account.publicKey,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                    systemProgram: SystemProgram.programId
+                })
+                .signers([user_account])
+                .rpc();
+        } catch (err) {
+            // should throw error
+            threwError = true;
+            assert.equal(err.error.errorCode.code, "SlippageExceeded", "Should fail with a slippage error");
+        }
+        assert.equal(threwError, true, "Should throw error when the swap output is below the minimum");
+
+        // Balances should be untouched
+        const userTokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const userTokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(userTokenAAccountInfo.amount, userTokenAAccountInfoBefore.amount, "Token A balance should not change");
+        assert.equal(userTokenBAccountInfo.amount, userTokenBAccountInfoBefore.amount, "Token B balance should not change");
+    });
+
+    it("can't take a referral fee without a referrer", async () => {
+        try {
+            await program.methods.swapTokens(new anchor.BN(1_000_000), false, new anchor.BN(0), 25, deadline())
+                .accountsStrict({
+                    liquidityPool: liquidityPoolPda,
+                    mintA: tokenA,
+                    userTokenA: userTokenAccountA.address,
+                    mintB: tokenB,
+                    userTokenB: userTokenAccountB.address,
+                    lpTokenA: lpTokenAccountA,
+                    lpTokenB: lpTokenAccountB,
+                    lpToken: lpToken,
+                    referrerTokenAccount: null,
+                    observations: observationsPda,
+                    user: user_account.publicKey,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                    systemProgram: SystemProgram.programId
+                })
+                .signers([user_account])
+                .rpc();
+            assert.fail("Swap should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "ReferrerMissing");
+        }
+    });
+
+    it("Can swap tokens for an exact output amount", async () => {
+        const userTokenAAccountInfoBefore = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const userTokenBAccountInfoBefore = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenAAccountInfo = await getAccount(provider.connection, lpTokenAccountA, undefined, TOKEN_2022_PROGRAM_ID);
+        const lpTokenBAccountInfo = await getAccount(provider.connection, lpTokenAccountB, undefined, TOKEN_2022_PROGRAM_ID);
+
+        const amount_out = BigInt(50_000);
+        // Inverse of the swap formula, rounded up
+        const numerator = lpTokenAAccountInfo.amount * amount_out * feeDenominator;
+        const denominator = (lpTokenBAccountInfo.amount - amount_out) * (feeDenominator - feePercentage);
+        const expectedAmountIn = (numerator + denominator - BigInt(1)) / denominator;
+
+        const signature = await program.methods.swapTokensExactOut(new anchor.BN(amount_out.toString()), new anchor.BN(expectedAmountIn.toString()), false, deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                referrerTokenAccount: null,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc({ commitment: "confirmed" });
+
+        const userTokenAAccountInfo = await getAccount(provider.connection, userTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        const userTokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(userTokenAAccountInfo.amount, userTokenAAccountInfoBefore.amount - expectedAmountIn, "Token A balance is incorrect");
+        assert.equal(userTokenBAccountInfo.amount, userTokenBAccountInfoBefore.amount + amount_out, "Token B balance is incorrect");
+
+        // The swap is reported as a typed event in the transaction logs
+        const transaction = await provider.connection.getTransaction(signature, { commitment: "confirmed", maxSupportedTransactionVersion: 0 });
+        const eventParser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+        const events = [...eventParser.parseLogs(transaction.meta.logMessages)];
+        const swapped = events.find((event) => event.name === "swapped");
+        assert.ok(swapped, "Swap should emit a Swapped event");
+        assert.ok(swapped.data.user.equals(user_account.publicKey), "Event user is incorrect");
+        assert.ok(swapped.data.mintIn.equals(tokenA), "Event input mint is incorrect");
+        assert.equal(swapped.data.amountIn.toString(), expectedAmountIn.toString(), "Event input amount is incorrect");
+        assert.equal(swapped.data.amountOut.toString(), amount_out.toString(), "Event output amount is incorrect");
+        const liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(swapped.data.reserveA.toString(), liquidityPoolAccount.reserveA.toString(), "Event reserve A is incorrect");
+        assert.equal(swapped.data.reserveB.toString(), liquidityPoolAccount.reserveB.toString(), "Event reserve B is incorrect");
+
+        // The pool keeps running totals of its swaps
+        assert.equal(liquidityPoolAccount.swapCount.toNumber(), 1, "Swap count is incorrect");
+        assert.equal(liquidityPoolAccount.volumeA.toString(), expectedAmountIn.toString(), "Token A volume is incorrect");
+        assert.equal(liquidityPoolAccount.volumeB.toString(), amount_out.toString(), "Token B volume is incorrect");
+        assert.equal(liquidityPoolAccount.feesA.toString(), swapped.data.fee.toString(), "Token A fees are incorrect");
+        assert.ok(liquidityPoolAccount.lastTradeTimestamp.toNumber() > 0, "Last trade timestamp should be set");
+    });
+
+    it("Quotes from the view functions match execution", async () => {
+        const amount_to_send = 1_000_000_000;
+        const addLiquidityQuote = await program.methods.quoteAddLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send))
+            .accountsStrict({ liquidityPool: liquidityPoolPda, lpToken: lpToken })
+            .view();
+        assert.equal(addLiquidityQuote.lpOut.toNumber(), amount_to_send - minimumLiquidity, "LP quote is incorrect");
+
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send), new anchor.BN(amount_to_send), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        const reserves = await program.methods.getReserves()
+            .accountsStrict({ liquidityPool: liquidityPoolPda, lpToken: lpToken })
+            .view();
+        assert.equal(reserves.reserveA.toNumber(), amount_to_send, "Reserve A is incorrect");
+        assert.equal(reserves.reserveB.toNumber(), amount_to_send, "Reserve B is incorrect");
+        assert.equal(reserves.lpSupply.toNumber(), amount_to_send, "LP supply is incorrect");
+
+        const spotPrice = await program.methods.getSpotPrice()
+            .accountsStrict({ liquidityPool: liquidityPoolPda, lpToken: lpToken })
+            .view();
+        assert.equal(spotPrice.priceA.toString(), spotPrice.priceB.toString(), "A balanced pool should price both tokens the same");
+
+        const removeLiquidityQuote = await program.methods.quoteRemoveLiquidity(new anchor.BN(amount_to_send / 2))
+            .accountsStrict({ liquidityPool: liquidityPoolPda, lpToken: lpToken })
+            .view();
+        assert.equal(removeLiquidityQuote.amountA.toNumber(), amount_to_send / 2, "Token A withdrawal quote is incorrect");
+        assert.equal(removeLiquidityQuote.amountB.toNumber(), amount_to_send / 2, "Token B withdrawal quote is incorrect");
+
+        const amount_to_swap = 100_000;
+        const swapQuote = await program.methods.quoteSwap(new anchor.BN(amount_to_swap), false)
+            .accountsStrict({ liquidityPool: liquidityPoolPda, lpToken: lpToken })
+            .view();
+        const userTokenBAccountInfoBefore = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        await program.methods.swapTokens(new anchor.BN(amount_to_swap), false, new anchor.BN(0), 0, deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                referrerTokenAccount: null,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+        const userTokenBAccountInfo = await getAccount(provider.connection, userTokenAccountB.address, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(userTokenBAccountInfo.amount - userTokenBAccountInfoBefore.amount, BigInt(swapQuote.amountOut.toString()), "Swap quote should match the output");
+    });
+
+    it("Can collect protocol fees", async () => {
+        const amount_to_send_a = 1_000_000_000;
+        const amount_to_send_b = 500_000_000;
+
+        await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                userLpTokenAccount: userAssociatedLPToken.address,
+                lockedLpTokenAccount: lockedLpTokenAccount,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        // The pool owner can't change the protocol fee
+        try {
+            await program.methods.setProtocolFee(5000)
+                .accountsStrict({
+                    globalConfig: globalConfigPda,
+                    admin: user_account.publicKey,
+                    liquidityPool: liquidityPoolPda,
+                })
+                .signers([user_account])
+                .rpc();
+            assert.fail("Setting the protocol fee should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "Unauthorized");
+        }
+
+        // Half of the swap fee goes to the protocol
+        await program.methods.setProtocolFee(5000)
+            .accountsStrict({
+                globalConfig: globalConfigPda,
+                admin: provider.wallet.publicKey,
+                liquidityPool: liquidityPoolPda,
+            })
+            .rpc();
+
+        const amount_to_swap = 1_000_000;
+        await program.methods.swapTokens(new anchor.BN(amount_to_swap), false, new anchor.BN(0), 0, deadline())
+            .accountsStrict({
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                userTokenA: userTokenAccountA.address,
+                mintB: tokenB,
+                userTokenB: userTokenAccountB.address,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                lpToken: lpToken,
+                referrerTokenAccount: null,
+                observations: observationsPda,
+                user: user_account.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                systemProgram: SystemProgram.programId
+            })
+            .signers([user_account])
+            .rpc();
+
+        // 1_000_000 * 0.3% * 50%
+        const expected_protocol_fee = 1500;
+        let liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.protocolFeesA.toNumber(), expected_protocol_fee, "Protocol fee A is incorrect");
+        assert.equal(liquidityPoolAccount.protocolFeesB.toNumber(), 0, "Protocol fee B is incorrect");
+
+        // Protocol fees can only go to token accounts owned by the treasury, which defaults to the admin
+        const globalConfig = await program.account.globalConfig.fetch(globalConfigPda);
+        const treasuryTokenAccountA = await getOrCreateAssociatedTokenAccount(
+            provider.connection,
+            user_account,
+            tokenA,
+            globalConfig.treasury,
+            true,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+        const treasuryTokenAccountB = await getOrCreateAssociatedTokenAccount(
+            provider.connection,
+            user_account,
+            tokenB,
+            globalConfig.treasury,
+            true,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+
+        try {
+            await program.methods.collectProtocolFees()
+                .accountsStrict({
+                    globalConfig: globalConfigPda,
+                    liquidityPool: liquidityPoolPda,
+                    mintA: tokenA,
+                    mintB: tokenB,
+                    lpTokenA: lpTokenAccountA,
+                    lpTokenB: lpTokenAccountB,
+                    treasuryTokenA: userTokenAccountA.address,
+                    treasuryTokenB: userTokenAccountB.address,
+                    admin: provider.wallet.publicKey,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                })
+                .rpc();
+            assert.fail("Collecting to another wallet should have failed");
+        } catch (err) {
+            assert.include(err.toString(), "InvalidTreasury");
+        }
+
+        const treasuryTokenAAccountInfoBefore = await getAccount(provider.connection, treasuryTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        await program.methods.collectProtocolFees()
+            .accountsStrict({
+                globalConfig: globalConfigPda,
+                liquidityPool: liquidityPoolPda,
+                mintA: tokenA,
+                mintB: tokenB,
+                lpTokenA: lpTokenAccountA,
+                lpTokenB: lpTokenAccountB,
+                treasuryTokenA: treasuryTokenAccountA.address,
+                treasuryTokenB: treasuryTokenAccountB.address,
+                admin: provider.wallet.publicKey,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            })
+            .rpc();
+
+        const treasuryTokenAAccountInfo = await getAccount(provider.connection, treasuryTokenAccountA.address, undefined, TOKEN_2022_PROGRAM_ID);
+        assert.equal(treasuryTokenAAccountInfo.amount, treasuryTokenAAccountInfoBefore.amount + BigInt(expected_protocol_fee), "Treasury did not receive the protocol fee");
+        liquidityPoolAccount = await program.account.liquidityPool.fetch(liquidityPoolPda);
+        assert.equal(liquidityPoolAccount.protocolFeesA.toNumber(), 0, "Protocol fee A should be reset");
+    });
+
     // TODO
     // it("Can't swap arbitrary tokens", async () => {
     //
@@ -866,7 +1945,7 @@ it("Can Add Liquidity", async () => {
     //     console.log(`User Token B Balance 1: ${userTokenBAccountInfo.amount}`);
     //
     //     // Add some tokens to the liquidity pool
-    //     await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b))
+    //     await program.methods.addLiquidity(new anchor.BN(amount_to_send_a), new anchor.BN(amount_to_send_b), new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
     //         .accountsStrict({
     //             liquidityPool: liquidityPoolPda,
     //             mintA: tokenA,
@@ -877,6 +1956,7 @@ it("Can Add Liquidity", async () => {
     //             lpTokenB: lpTokenAccountB,
     //             lpToken: lpToken,
     //             userLpTokenAccount: userAssociatedLPToken.address,
+    //             lockedLpTokenAccount: lockedLpTokenAccount,
     //             user: user_account.publicKey,
     //             tokenProgram: TOKEN_2022_PROGRAM_ID,
     //             systemProgram: SystemProgram.programId
@@ -899,7 +1979,7 @@ it("Can Add Liquidity", async () => {
     //     let threwError = false;
     //     try {
     //         const amount_to_swap = 534_321;
-    //         await program.methods.swapTokens(new anchor.BN(amount_to_swap))
+    //         await program.methods.swapTokens(new anchor.BN(amount_to_swap), false, new anchor.BN(0), 0, deadline())
     //             .accountsStrict({
     //                 liquidityPool: liquidityPoolPda,
     //                 // This will be standard so that token A is swapped for token b
@@ -910,6 +1990,7 @@ it("Can Add Liquidity", async () => {
     //                 lpTokenA: lpTokenAccountC.address,
     //                 lpTokenB: lpTokenAccountB,
     //                 lpToken: lpToken,
+    //                 referrerTokenAccount: null,
     //                 user: user_account.publicKey,
     //                 tokenProgram: TOKEN_2022_PROGRAM_ID,
     //                 systemProgram: SystemProgram.programId